@@ -1,6 +1,32 @@
 use std::fs::File;
 use std::io::Read;
 use serde::{Deserialize, Serialize};
+use crate::mqtt::tools::topic::{TopicLimits, MAX_TOPIC_LENGTH};
+
+///
+/// QoS 1/2 消息未确认时的默认重发间隔（秒）
+///
+const DEFAULT_RETRY_INTERVAL: u64 = 20;
+
+///
+/// 每个离线会话默认最多缓存的消息数
+///
+const DEFAULT_MAX_QUEUED_MESSAGES: usize = 1000;
+
+///
+/// 主题默认的最大层级数
+///
+const DEFAULT_MAX_TOPIC_LEVELS: usize = 128;
+
+///
+/// v5 客户端默认可以使用的主题别名数量
+///
+const DEFAULT_TOPIC_ALIAS_MAXIMUM: u16 = 10;
+
+///
+/// 默认允许的最大报文长度（字节）
+///
+const DEFAULT_MAX_PACKET_SIZE: u32 = 1048576;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
@@ -11,20 +37,86 @@ pub struct Config {
 }
 
 impl Config {
+    fn http(&self) -> &HttpParam {
+        self.http.as_ref().expect("missing [http] section in config")
+    }
+
+    fn mqtt(&self) -> &MqttParam {
+        self.mqtt.as_ref().expect("missing [mqtt] section in config")
+    }
+
     pub fn get_http_ip(&self) -> &String {
-        &self.http.as_ref().expect("get http ip is error").ip
+        &self.http().ip
     }
 
     pub fn get_http_port(&self) -> u16 {
-        self.http.as_ref().expect("get http ip is error").port
+        self.http().port
+    }
+
+    ///
+    /// HTTP 接口发布的消息默认的有效时间（秒），不配置时永不过期
+    ///
+    pub fn get_http_message_expiry_interval(&self) -> Option<u32> {
+        self.http().message_expiry_interval
     }
 
     pub fn get_mqtt_ip(&self) -> &String {
-        &self.mqtt.as_ref().expect("get mqtt ip is error").ip
+        &self.mqtt().ip
     }
 
     pub fn get_mqtt_port(&self) -> u16 {
-        self.mqtt.as_ref().expect("get mqtt ip is error").port
+        self.mqtt().port
+    }
+
+    ///
+    /// 重发间隔（秒），至少为 1 秒
+    ///
+    pub fn get_mqtt_retry_interval(&self) -> u64 {
+        self.mqtt().retry_interval.unwrap_or(DEFAULT_RETRY_INTERVAL).max(1)
+    }
+
+    pub fn get_mqtt_max_queued_messages(&self) -> usize {
+        self.mqtt().max_queued_messages.unwrap_or(DEFAULT_MAX_QUEUED_MESSAGES)
+    }
+
+    ///
+    /// 主题名与主题过滤器的长度、层级限制
+    ///
+    pub fn get_mqtt_topic_limits(&self) -> TopicLimits {
+        let mqtt = self.mqtt();
+        TopicLimits::new(
+            mqtt.max_topic_length.unwrap_or(MAX_TOPIC_LENGTH).min(MAX_TOPIC_LENGTH),
+            mqtt.max_topic_levels.unwrap_or(DEFAULT_MAX_TOPIC_LEVELS),
+        )
+    }
+
+    ///
+    /// 服务端允许的最长会话过期时间（秒），不配置时不限制
+    ///
+    pub fn get_mqtt_max_session_expiry_interval(&self) -> u32 {
+        self.mqtt().max_session_expiry_interval.unwrap_or(u32::MAX)
+    }
+
+    pub fn get_mqtt_topic_alias_maximum(&self) -> u16 {
+        self.mqtt().topic_alias_maximum.unwrap_or(DEFAULT_TOPIC_ALIAS_MAXIMUM)
+    }
+
+    ///
+    /// 客户端发送的报文允许的最大长度，v5 客户端通过 CONNACK 获知
+    ///
+    pub fn get_mqtt_max_packet_size(&self) -> u32 {
+        self.mqtt().max_packet_size.unwrap_or(DEFAULT_MAX_PACKET_SIZE)
+    }
+
+    ///
+    /// 配置了用户名时，客户端必须携带匹配的用户名和密码才能连接
+    ///
+    pub fn get_mqtt_username(&self) -> Option<&str> {
+        self.mqtt().username.as_deref()
+    }
+
+    pub fn get_mqtt_password(&self) -> Option<&str> {
+        self.mqtt().password.as_deref()
     }
 
     pub fn get_preload_url(&self) -> &str {
@@ -40,12 +132,24 @@ impl Config {
 pub struct HttpParam {
     pub ip: String,
     pub port: u16,
+    pub message_expiry_interval: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MqttParam {
     pub ip: String,
     pub port: u16,
+    pub retry_interval: Option<u64>,
+    pub max_queued_messages: Option<usize>,
+    pub max_topic_length: Option<usize>,
+    pub max_topic_levels: Option<usize>,
+    pub topic_alias_maximum: Option<u16>,
+    pub max_session_expiry_interval: Option<u32>,
+    pub max_packet_size: Option<u32>,
+    /// 配置后客户端必须携带该用户名才能连接，不配置时不做认证
+    pub username: Option<String>,
+    /// 与 username 一起校验的密码，不配置时客户端不能携带密码
+    pub password: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -72,3 +176,16 @@ pub fn load_config_file() -> Config {
     let file = read_file("./config/server.toml").expect("read config file error");
     return toml::from_str(String::from(file).trim()).expect("parse config file error");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_interval() {
+        let config: Config = toml::from_str("[mqtt]\nip = '127.0.0.1'\nport = 1883\nretry_interval = 0").unwrap();
+        assert_eq!(config.get_mqtt_retry_interval(), 1);
+        let config: Config = toml::from_str("[mqtt]\nip = '127.0.0.1'\nport = 1883").unwrap();
+        assert_eq!(config.get_mqtt_retry_interval(), DEFAULT_RETRY_INTERVAL);
+    }
+}
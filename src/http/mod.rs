@@ -5,10 +5,13 @@ use axum::{Router, Json};
 use axum::http::StatusCode;
 use serde::{Serialize, Deserialize};
 
-use crate::{CONFIG, MACHINE_CONTAINER, SUBSCRIPT, MachineID};
+use crate::{CONFIG, MACHINE_CONTAINER, RETAIN, MachineID};
 use axum::extract::Query;
-use crate::mqtt::v3_server::{TopicMessage, ClientID};
-use crate::mqtt::message::v3;
+use crate::mqtt::v3_server::{TopicMessage, ClientID, publish};
+use crate::mqtt::message::application::ApplicationMessage;
+use crate::mqtt::tools::protocol::{MqttQos, MqttRetain};
+use crate::mqtt::tools::topic;
+use std::collections::HashMap;
 use log::{info, debug};
 use std::str::FromStr;
 
@@ -30,6 +33,12 @@ impl Default for SimpleDataResult {
     }
 }
 
+impl SimpleDataResult {
+    pub fn fail<S: Into<String>>(message: S) -> Self {
+        SimpleDataResult { code: 0, message: message.into() }
+    }
+}
+
 impl<T: Serialize> DataResult<T> {
     pub fn new(data: T) -> Self {
         DataResult { code: 1, data: Some(data) }
@@ -87,6 +96,32 @@ impl From<MachineLogin> for MachineMessage {
     }
 }
 
+#[derive(Serialize, Debug)]
+struct RetainMessage {
+    client_id: String,
+    qos: u8,
+    payload: RetainPayload,
+}
+
+///
+/// 保留消息载荷，UTF-8 文本直接以字符串返回，其它以字节数组返回
+///
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum RetainPayload {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<Vec<u8>> for RetainPayload {
+    fn from(payload: Vec<u8>) -> Self {
+        match String::from_utf8(payload) {
+            Ok(text) => RetainPayload::Text(text),
+            Err(e) => RetainPayload::Binary(e.into_bytes()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct MachineQrcode {
     id: String,
@@ -105,7 +140,8 @@ pub async fn http_server() {
         .route("/", get(root))
         .route("/machines", get(get_machines))
         .route("/set_machine_qrcode", get(set_machine_qrcode))
-        .route("/machine_login", get(machine_login));
+        .route("/machine_login", get(machine_login))
+        .route("/retains", get(get_retains));
 
     let socket = SocketAddrV4::new(
         Ipv4Addr::from_str(CONFIG.get_http_ip()).unwrap(),
@@ -132,14 +168,28 @@ async fn get_machines() -> impl IntoResponse {
 }
 
 ///
-/// 设置机器二维码
+/// 返回保留消息列表
+///
+async fn get_retains() -> impl IntoResponse {
+    let retains = RETAIN.messages().await.into_iter()
+        .map(|(topic, TopicMessage::Content(client_id, content))| (topic, RetainMessage {
+            client_id: client_id.as_string(),
+            qos: content.qos.as_byte(),
+            payload: RetainPayload::from(content.payload),
+        }))
+        .collect::<HashMap<String, RetainMessage>>();
+    (StatusCode::OK, Json(DataResult::new(retains)))
+}
+
+///
+/// 设置机器二维码，以保留消息发布，机器上线订阅后即可收到
 ///
 async fn set_machine_qrcode(Query(payload): Query<MachineQrcode>) -> impl IntoResponse {
     debug!("{:?}",payload);
     let entity = MachineMessage::from(payload);
     let id = MachineID(entity.id.clone());
     MACHINE_CONTAINER.set_qrcode(&id, entity.data.clone()).await;
-    broadcast(entity).await
+    broadcast(entity, MqttRetain::Enable).await
 }
 
 ///
@@ -148,19 +198,21 @@ async fn set_machine_qrcode(Query(payload): Query<MachineQrcode>) -> impl IntoRe
 async fn machine_login(Query(payload): Query<MachineLogin>) -> impl IntoResponse {
     debug!("{:?}",payload);
     let entity = MachineMessage::from(payload);
-    broadcast(entity).await
+    broadcast(entity, MqttRetain::Disable).await
 }
 
-async fn broadcast(machine_message: MachineMessage) -> (StatusCode, Json<SimpleDataResult>) {
+async fn broadcast(machine_message: MachineMessage, retain: MqttRetain) -> (StatusCode, Json<SimpleDataResult>) {
     let topic = format!("{}-topic", machine_message.id.clone());
-    let publish_message = v3::PublishMessage::simple_new_msg(
-        topic,
-        0,
-        serde_json::to_string(&machine_message).unwrap(),
-    );
-    let topic_msg = TopicMessage::ContentV3(ClientID("idreamspace-server".to_string()), publish_message);
-    if let Some(topic) = topic_msg.get_topic() {
-        SUBSCRIPT.broadcast(topic, &topic_msg).await;
+    if let Err(e) = topic::validate_topic_name(&topic, &CONFIG.get_mqtt_topic_limits()) {
+        info!("invalid publish topic {:?}: {}", topic, e);
+        return (StatusCode::BAD_REQUEST, Json(SimpleDataResult::fail(e.to_string())));
     }
+    let msg = ApplicationMessage::new(
+        topic,
+        serde_json::to_vec(&machine_message).unwrap(),
+        MqttQos::Qos1,
+        retain,
+    ).with_expiry(CONFIG.get_http_message_expiry_interval());
+    publish(ClientID("idreamspace-server".to_string()), msg).await;
     (StatusCode::OK, Json(SimpleDataResult::default()))
 }
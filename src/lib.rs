@@ -6,6 +6,9 @@ pub mod http;
 mod config;
 
 use crate::mqtt::v3_server::Subscript;
+use crate::mqtt::retain::Retain;
+use crate::mqtt::session::SessionContainer;
+use crate::mqtt::client::ClientContainer;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -17,6 +20,9 @@ use crate::config::{Config, load_config_file};
 lazy_static! {
     pub static ref CONFIG: Config = load_config_file();
     pub static ref SUBSCRIPT: Subscript = Subscript::new();
+    pub static ref RETAIN: Retain = Retain::new();
+    pub static ref SESSIONS: SessionContainer = SessionContainer::new();
+    pub static ref CLIENTS: ClientContainer = ClientContainer::new();
     pub static ref MACHINE_CONTAINER: MachineContainer = MachineContainer::new();
 }
 
@@ -0,0 +1,187 @@
+use crate::mqtt::v3_server::{ClientID, LineMessage};
+use crate::CONFIG;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::Sender;
+
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+///
+/// 服务端分配的客户端标识前缀
+///
+pub const ASSIGNED_CLIENT_ID_PREFIX: &str = "auto-";
+
+lazy_static! {
+    static ref STARTED_AT: u128 = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default();
+}
+
+///
+/// 为每个连接分配唯一编号，用于区分同一客户端标识的新旧连接
+///
+pub fn next_connection_id() -> u64 {
+    CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+///
+/// 客户端标识为空时由服务端分配，带上启动时间避免与重启前分配的标识重复
+///
+pub fn assign_client_id(connection_id: u64) -> ClientID {
+    ClientID(format!("{}{:x}-{:x}", ASSIGNED_CLIENT_ID_PREFIX, *STARTED_AT, connection_id))
+}
+
+///
+/// 用户名密码校验失败的原因
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Credentials {
+    /// 服务端要求认证，但客户端没有携带用户名
+    Missing,
+    /// 用户名或密码不匹配
+    Invalid,
+}
+
+///
+/// 校验用户名和密码，服务端没有配置用户名时不做校验
+///
+pub fn check_credentials(user_name: Option<&str>, password: Option<&[u8]>) -> Option<Credentials> {
+    verify_credentials(CONFIG.get_mqtt_username(), CONFIG.get_mqtt_password(), user_name, password)
+}
+
+fn verify_credentials(expected_user_name: Option<&str>, expected_password: Option<&str>, user_name: Option<&str>, password: Option<&[u8]>) -> Option<Credentials> {
+    let expected = expected_user_name?;
+    match user_name {
+        None => Some(Credentials::Missing),
+        Some(user_name) => {
+            if user_name == expected && password == expected_password.map(str::as_bytes) {
+                None
+            } else {
+                Some(Credentials::Invalid)
+            }
+        }
+    }
+}
+
+///
+/// 在线的连接
+///
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub id: u64,
+    pub sender: Sender<LineMessage>,
+}
+
+impl Connection {
+    pub fn new(id: u64, sender: Sender<LineMessage>) -> Connection {
+        Connection { id, sender }
+    }
+}
+
+///
+/// 客户端标识与当前在线连接的对应关系
+///
+pub struct ClientContainer {
+    container: Arc<Mutex<HashMap<ClientID, Connection>>>,
+}
+
+impl Default for ClientContainer {
+    fn default() -> Self {
+        ClientContainer::new()
+    }
+}
+
+impl ClientContainer {
+    pub fn new() -> ClientContainer {
+        ClientContainer { container: Arc::new(Mutex::new(HashMap::default())) }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.container.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.container.lock().await.is_empty()
+    }
+
+    pub async fn contain<S: AsRef<ClientID>>(&self, client_id: S) -> bool {
+        self.container.lock().await.contains_key(client_id.as_ref())
+    }
+
+    pub async fn get<S: AsRef<ClientID>>(&self, client_id: S) -> Option<Connection> {
+        self.container.lock().await.get(client_id.as_ref()).cloned()
+    }
+
+    ///
+    /// 客户端标识当前是否登记在该连接上
+    ///
+    pub async fn is_current<S: AsRef<ClientID>>(&self, client_id: S, connection_id: u64) -> bool {
+        self.container.lock().await.get(client_id.as_ref()).is_some_and(|connection| connection.id == connection_id)
+    }
+
+    ///
+    /// 登记新连接，返回同一客户端标识之前的连接
+    ///
+    pub async fn register(&self, client_id: ClientID, connection: Connection) -> Option<Connection> {
+        self.container.lock().await.insert(client_id, connection)
+    }
+
+    ///
+    /// 连接关闭时注销，已被新连接接管时不做处理
+    ///
+    pub async fn unregister<S: AsRef<ClientID>>(&self, client_id: S, connection_id: u64) -> bool {
+        let mut container = self.container.lock().await;
+        match container.get(client_id.as_ref()) {
+            Some(connection) if connection.id == connection_id => {
+                container.remove(client_id.as_ref());
+                true
+            }
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_credentials() {
+        // 没有配置用户名时不校验
+        assert_eq!(verify_credentials(None, None, None, None), None);
+        assert_eq!(verify_credentials(None, None, Some("kiosk"), Some(b"x")), None);
+
+        assert_eq!(verify_credentials(Some("kiosk"), Some("secret"), None, None), Some(Credentials::Missing));
+        assert_eq!(verify_credentials(Some("kiosk"), Some("secret"), Some("kiosk"), Some(b"wrong")), Some(Credentials::Invalid));
+        assert_eq!(verify_credentials(Some("kiosk"), Some("secret"), Some("other"), Some(b"secret")), Some(Credentials::Invalid));
+        assert_eq!(verify_credentials(Some("kiosk"), Some("secret"), Some("kiosk"), None), Some(Credentials::Invalid));
+        assert_eq!(verify_credentials(Some("kiosk"), Some("secret"), Some("kiosk"), Some(b"secret")), None);
+        // 只配置用户名时客户端不能携带密码
+        assert_eq!(verify_credentials(Some("kiosk"), None, Some("kiosk"), None), None);
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let clients = ClientContainer::new();
+        let (sender, _receiver) = mpsc::channel(1);
+        let client_id = ClientID::from("kiosk-1");
+        let first = next_connection_id();
+        let second = next_connection_id();
+        assert_ne!(first, second);
+        assert_ne!(assign_client_id(first), assign_client_id(second));
+        assert!(assign_client_id(first).0.starts_with(ASSIGNED_CLIENT_ID_PREFIX));
+
+        assert!(clients.register(client_id.clone(), Connection::new(first, sender.clone())).await.is_none());
+        let previous = clients.register(client_id.clone(), Connection::new(second, sender)).await;
+        assert_eq!(previous.map(|connection| connection.id), Some(first));
+
+        assert!(!clients.is_current(&client_id, first).await);
+        assert!(clients.is_current(&client_id, second).await);
+        assert!(!clients.unregister(&client_id, first).await);
+        assert!(clients.contain(&client_id).await);
+        assert!(clients.unregister(&client_id, second).await);
+        assert!(clients.is_empty().await);
+    }
+}
@@ -1,6 +1,7 @@
 use num_enum::TryFromPrimitive;
-use crate::mqtt::tools::un_pack_tool::{parse_long_int, parse_string, parse_byte, parse_short_int, unpack_var_int};
-use crate::mqtt::tools::pack_tool::{pack_long_int, pack_string, pack_byte, pack_short_int, pack_var_int};
+use crate::mqtt::tools::un_pack_tool::{parse_long_int, parse_string, parse_binary, parse_string_pair, parse_byte, parse_short_int, parse_var_int};
+use crate::mqtt::tools::error::DecodeError;
+use crate::mqtt::tools::pack_tool::{pack_long_int, pack_string, pack_binary, pack_string_pair, pack_byte, pack_short_int, pack_var_int};
 
 
 pub mod reason_code;
@@ -13,6 +14,7 @@ pub enum PropertyValue {
     Short(u16),
     Byte(u8),
     String(String),
+    Binary(Vec<u8>),
     Map(String, String),
 }
 
@@ -56,6 +58,15 @@ impl PropertyItem {
         }
     }
 
+    pub fn as_binary(&self) -> Option<&Vec<u8>> {
+        match self.1 {
+            PropertyValue::Binary(ref val) => {
+                Some(val)
+            }
+            _ => { None }
+        }
+    }
+
     pub fn as_map(&self) -> Option<(&String, &String)> {
         match self.1 {
             PropertyValue::Map(ref key, ref value) => {
@@ -66,7 +77,14 @@ impl PropertyItem {
     }
 }
 
-#[derive(Debug, Copy, Clone, TryFromPrimitive)]
+///
+/// 在属性列表中查找指定的属性
+///
+pub fn find_property(items: &[PropertyItem], property: Property) -> Option<&PropertyItem> {
+    items.iter().find(|item| item.0 == property)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Property {
     PayloadFormatIndicator = 0x01,
@@ -141,7 +159,7 @@ impl Property {
             Property::RequestProblemInformation |
             Property::RequestResponseInformation |
             Property::ReceiveMaximum |
-            Property::TopicAlias |
+            Property::TopicAliasMaximum |
             Property::UserProperty |
             Property::MaximumPacketSize => { true }
             _ => { false }
@@ -243,7 +261,7 @@ impl Property {
 }
 
 impl Property {
-    pub fn pack_property_handle(item: &PropertyItem, length: &mut usize, body: &mut Vec<u8>) {
+    pub fn pack_property_handle(item: &PropertyItem, body: &mut Vec<u8>) {
         body.push(item.0 as u8);
 
         match item.0 {
@@ -251,22 +269,20 @@ impl Property {
             Property::MessageExpiryInterval |
             Property::WillDelayInterval |
             Property::MaximumPacketSize => {
-                let long_val = pack_long_int(item.as_long().unwrap());
-                *length += 5;
-                body.extend(long_val);
+                body.extend(pack_long_int(item.as_long().unwrap()));
             }
             Property::ContentType |
             Property::ResponseTopic |
-            Property::CorrelationData |
             Property::AssignedClientIdentifier |
             Property::ResponseInformation |
             Property::ServerReference |
             Property::ReasonString |
-            Property::AuthenticationMethod |
+            Property::AuthenticationMethod => {
+                body.extend(pack_string(item.as_str().unwrap()));
+            }
+            Property::CorrelationData |
             Property::AuthenticationData => {
-                let string_val = pack_string(item.as_str().unwrap());
-                *length += string_val.len() + 3;
-                body.extend(string_val);
+                body.extend(pack_binary(item.as_binary().unwrap()));
             }
             Property::PayloadFormatIndicator |
             Property::MaximumQos |
@@ -276,57 +292,47 @@ impl Property {
             Property::SharedSubscriptionAvailable |
             Property::RequestProblemInformation |
             Property::RequestResponseInformation => {
-                let byte_val = pack_byte(item.as_byte().unwrap());
-                *length += 2;
-                body.extend(byte_val);
+                body.extend(pack_byte(item.as_byte().unwrap()));
             }
             Property::ServerKeepAlive |
             Property::ReceiveMaximum |
             Property::TopicAlias |
             Property::TopicAliasMaximum => {
-                let short_val = pack_short_int(item.as_short().unwrap());
-                *length += 3;
-                body.extend(short_val);
+                body.extend(pack_short_int(item.as_short().unwrap()));
             }
             Property::UserProperty => {
-                let user = item.as_map();
-                let user_key = pack_string(user.as_ref().unwrap().0);
-                let user_value = pack_string(user.as_ref().unwrap().1);
-                *length += user_key.len() + user_value.len() + 5;
-                body.push(Property::UserProperty as u8);
-                body.extend(user_key);
-                body.extend(user_value);
+                let (key, value) = item.as_map().unwrap();
+                body.extend(pack_string_pair(key, value));
             }
             Property::SubscriptionIdentifier => {
-                let si = pack_var_int(1);
-                *length += si.len() + 1;
-                body.extend(si);
+                body.extend(pack_var_int(item.as_long().unwrap() as usize));
             }
         }
     }
 
-    pub fn unpack_property_handle<'a>(&self, length: &mut u32, data: &'a [u8]) -> Option<(PropertyItem, &'a [u8])> {
+    pub fn unpack_property_handle<'a>(&self, data: &'a [u8]) -> Result<(PropertyItem, &'a [u8]), DecodeError> {
         match self {
             Property::SessionExpiryInterval |
             Property::MessageExpiryInterval |
             Property::WillDelayInterval |
             Property::MaximumPacketSize => {
-                let (val, last_data) = parse_long_int(data);
-                *length -= 5;
-                Some((PropertyItem(Property::SessionExpiryInterval, PropertyValue::Long(val)), last_data))
+                let (val, last_data) = parse_long_int(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Long(val)), last_data))
             }
             Property::ContentType |
             Property::ResponseTopic |
-            Property::CorrelationData |
             Property::AssignedClientIdentifier |
             Property::ResponseInformation |
             Property::ServerReference |
             Property::ReasonString |
-            Property::AuthenticationMethod |
+            Property::AuthenticationMethod => {
+                let (val, last_data) = parse_string(data)?;
+                Ok((PropertyItem(*self, PropertyValue::String(val)), last_data))
+            }
+            Property::CorrelationData |
             Property::AuthenticationData => {
-                let (val, last_data) = parse_string(data).unwrap();
-                *length -= val.len() as u32 + 3;
-                Some((PropertyItem(*self, PropertyValue::String(val)), last_data.unwrap()))
+                let (val, last_data) = parse_binary(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Binary(val)), last_data))
             }
             Property::PayloadFormatIndicator |
             Property::MaximumQos |
@@ -336,29 +342,54 @@ impl Property {
             Property::SharedSubscriptionAvailable |
             Property::RequestProblemInformation |
             Property::RequestResponseInformation => {
-                let (val, last_data) = parse_byte(data);
-                *length -= 2;
-                Some((PropertyItem(*self, PropertyValue::Byte(val)), last_data))
+                let (val, last_data) = parse_byte(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Byte(val)), last_data))
             }
             Property::ServerKeepAlive |
             Property::ReceiveMaximum |
             Property::TopicAlias |
             Property::TopicAliasMaximum => {
-                let (val, last_data) = parse_short_int(data);
-                *length -= 3;
-                Some((PropertyItem(*self, PropertyValue::Short(val)), last_data))
+                let (val, last_data) = parse_short_int(data)?;
+                Ok((PropertyItem(*self, PropertyValue::Short(val)), last_data))
             }
             Property::UserProperty => {
-                let (user_key, last_data) = parse_string(data).unwrap();
-                let (user_value, last_data) = parse_string(last_data.unwrap()).unwrap();
-                *length -= user_key.len() as u32 + user_value.len() as u32 + 5;
-                Some((PropertyItem(Property::UserProperty, PropertyValue::Map(user_key, user_value)), last_data.unwrap()))
+                let ((user_key, user_value), last_data) = parse_string_pair(data)?;
+                Ok((PropertyItem(Property::UserProperty, PropertyValue::Map(user_key, user_value)), last_data))
             }
             Property::SubscriptionIdentifier => {
-                let (val, last_data) = unpack_var_int(data);
-                *length -= val.len() as u32 + 1;
-                Some((PropertyItem(Property::SubscriptionIdentifier, PropertyValue::String(val)), last_data))
+                let (val, last_data) = parse_var_int(data)?;
+                if val == 0 {
+                    return Err(DecodeError::Malformed("subscription identifier is zero"));
+                }
+                Ok((PropertyItem(Property::SubscriptionIdentifier, PropertyValue::Long(val as u32)), last_data))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let properties = vec![
+            PropertyItem(Property::MessageExpiryInterval, PropertyValue::Long(60)),
+            PropertyItem(Property::ResponseTopic, PropertyValue::String("r".repeat(300))),
+            PropertyItem(Property::CorrelationData, PropertyValue::Binary(vec![0, 159, 146, 150])),
+            PropertyItem(Property::UserProperty, PropertyValue::Map("key".to_string(), "value".to_string())),
+            PropertyItem(Property::SubscriptionIdentifier, PropertyValue::Long(268_435_455)),
+        ];
+        let bytes = pack_property::publish(&properties);
+        let (length, data) = parse_var_int(bytes.as_slice()).unwrap();
+        assert_eq!(length, data.len());
+
+        let unpacked = un_pack_property::publish(length as u32, data).unwrap();
+        assert_eq!(unpacked.len(), properties.len());
+        assert_eq!(unpacked[0].as_long(), Some(60));
+        assert_eq!(unpacked[1].as_str().map(String::len), Some(300));
+        assert_eq!(unpacked[2].as_binary(), Some(&vec![0, 159, 146, 150]));
+        assert_eq!(unpacked[3].as_map(), Some((&"key".to_string(), &"value".to_string())));
+        assert_eq!(unpacked[4].as_long(), Some(268_435_455));
+    }
+}
@@ -1,106 +1,49 @@
 use crate::mqtt::hex::{PropertyItem, Property};
+use crate::mqtt::tools::pack_tool::pack_var_int;
 
-pub fn connect(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
+///
+/// 包装属性列表，属性长度为变长整数
+///
+fn pack_properties(data: &[PropertyItem], is_allowed: fn(&Property) -> bool) -> Vec<u8> {
     let mut body = vec![];
     for item in data {
-        if item.0.is_connect_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
+        if is_allowed(&item.0) {
+            Property::pack_property_handle(item, &mut body);
         }
     }
-    body.insert(0, length as u8);
-    body
+    let mut package = pack_var_int(body.len());
+    package.extend(body);
+    package
 }
 
-pub fn connack(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-    for item in data {
-        if item.0.is_connack_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn connect(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_connect_property)
 }
 
-pub fn will_properties(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_will_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn connack(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_connack_property)
 }
 
-pub fn subscribe(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_subscribe_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn will_properties(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_will_property)
 }
 
-pub fn suback(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_pub_and_sub_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn subscribe(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_subscribe_property)
 }
 
-pub fn disconnect(data: &Vec<PropertyItem>) -> Vec<u8> {
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_disconnect_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn suback(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_pub_and_sub_property)
 }
 
-pub fn auth(data: &Vec<PropertyItem>) -> Vec<u8>{
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_auth_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn disconnect(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_disconnect_property)
 }
 
-pub fn publish(data: &Vec<PropertyItem>) -> Vec<u8>{
-    let mut length = 0_usize;
-    let mut body = vec![];
-
-    for item in data {
-        if item.0.is_publish_property() {
-            Property::pack_property_handle(item, &mut length, &mut body);
-        }
-    }
-    body.insert(0, length as u8);
-    body
+pub fn auth(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_auth_property)
 }
 
-
-
+pub fn publish(data: &[PropertyItem]) -> Vec<u8> {
+    pack_properties(data, Property::is_publish_property)
+}
@@ -1,288 +1,67 @@
 use crate::mqtt::hex::{PropertyItem, Property};
+use crate::mqtt::tools::error::DecodeError;
+use crate::mqtt::tools::un_pack_tool::parse_byte;
 use std::convert::TryFrom;
-use log::{ error};
 
-pub fn connect(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
+///
+/// 解析属性列表，遇到未知或当前报文不允许的属性时返回错误
+///
+fn unpack_properties(length: u32, data: &[u8], is_allowed: fn(&Property) -> bool) -> Result<Vec<PropertyItem>, DecodeError> {
     let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_connect_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
+    let mut data = data.get(..length as usize).ok_or(DecodeError::Truncated)?;
+    while !data.is_empty() {
+        let (property, last_data) = parse_byte(data)?;
+        let p = Property::try_from(property).map_err(|_| DecodeError::UnknownProperty(property))?;
+        if !is_allowed(&p) {
+            return Err(DecodeError::InvalidProperty(property));
+        }
+        let (item, last_data) = p.unpack_property_handle(last_data)?;
+        data = last_data;
+        properties.push(item);
     }
-    properties
+    Ok(properties)
 }
 
-pub fn connack(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_connack_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn connect(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_connect_property)
 }
 
-pub fn publish(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_publish_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn connack(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_connack_property)
 }
 
-pub fn subscribe(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_subscribe_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn publish(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_publish_property)
 }
 
-pub fn unsubscribe(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_unsubscribe_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn subscribe(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_subscribe_property)
 }
 
-pub fn suback(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_pub_and_sub_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn unsubscribe(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_unsubscribe_property)
 }
 
-pub fn unsuback(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_pub_and_sub_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn suback(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_pub_and_sub_property)
 }
 
-pub fn disconnect(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_disconnect_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn unsuback(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_pub_and_sub_property)
 }
 
-pub fn auth(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_auth_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn disconnect(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_disconnect_property)
 }
 
-pub fn pub_and_sub(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_pub_and_sub_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
-
-    properties
+pub fn auth(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_auth_property)
 }
 
-pub fn will_properties(mut length: u32, mut data: &[u8]) -> Vec<PropertyItem> {
-    let mut properties = vec![];
-    loop {
-        let property = data[0];
-        match Property::try_from(property) {
-            Ok(p) => {
-                if p.is_will_property() {
-                    if let Some((item, last_data)) = p.unpack_property_handle(&mut length, data.get(1..).unwrap()) {
-                        data = last_data;
-                        properties.push(item);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Property {:?} not exist", e)
-            }
-        }
-
-        if length <= 0 {
-            break;
-        }
-    }
+pub fn pub_and_sub(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_pub_and_sub_property)
+}
 
-    properties
+pub fn will_properties(length: u32, data: &[u8]) -> Result<Vec<PropertyItem>, DecodeError> {
+    unpack_properties(length, data, Property::is_will_property)
 }
@@ -0,0 +1,143 @@
+use crate::mqtt::message::{v3, v5, MqttBytesMessage};
+use crate::mqtt::tools::protocol::{MqttProtocolLevel, MqttQos, MqttRetain, MqttDup};
+use crate::mqtt::hex::{Property, PropertyItem, PropertyValue};
+use std::time::Duration;
+use tokio::time::Instant;
+
+///
+/// 与协议版本无关的应用消息，服务端内部按此格式路由、保留和缓存，
+/// 发送给订阅者时再按其协议版本重新打包
+///
+#[derive(Debug, Clone)]
+pub struct ApplicationMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: MqttQos,
+    pub retain: MqttRetain,
+    /// 需要转发给 v5 订阅者的属性，v3 订阅者会忽略
+    pub properties: Vec<PropertyItem>,
+    /// 消息的过期时间，发送给 v5 订阅者时改写为剩余的 MessageExpiryInterval
+    pub expires_at: Option<Instant>,
+}
+
+impl ApplicationMessage {
+    pub fn new<S: Into<String>>(topic: S, payload: Vec<u8>, qos: MqttQos, retain: MqttRetain) -> ApplicationMessage {
+        ApplicationMessage { topic: topic.into(), payload, qos, retain, properties: vec![], expires_at: None }
+    }
+
+    ///
+    /// 设置发布者携带的属性，MessageExpiryInterval 转换为过期时间，
+    /// 只在发布者与服务端之间有效的属性不会保留
+    ///
+    pub fn with_properties(mut self, properties: Vec<PropertyItem>) -> ApplicationMessage {
+        let interval = properties.iter()
+            .find(|item| item.0 == Property::MessageExpiryInterval)
+            .and_then(PropertyItem::as_long);
+        self.properties = properties.into_iter().filter(is_forwarded).collect::<Vec<PropertyItem>>();
+        self.with_expiry(interval)
+    }
+
+    ///
+    /// 设置消息的有效时间（秒），None 表示永不过期
+    ///
+    pub fn with_expiry(mut self, interval: Option<u32>) -> ApplicationMessage {
+        self.expires_at = interval.map(|interval| Instant::now() + Duration::from_secs(interval as u64));
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() >= expires_at,
+            None => false
+        }
+    }
+
+    ///
+    /// 剩余的有效时间（秒），不足一秒按一秒计算
+    ///
+    pub fn remaining_expiry(&self) -> Option<u32> {
+        let remaining = self.expires_at?.saturating_duration_since(Instant::now());
+        let seconds = remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 };
+        Some(seconds.min(u32::MAX as u64) as u32)
+    }
+
+    ///
+    /// 替换消息携带的订阅标识符，共享订阅的消息交给其它成员时使用该成员的订阅标识符
+    ///
+    pub fn with_subscription_identifiers(&self, identifiers: &[u32]) -> ApplicationMessage {
+        let mut msg = self.clone();
+        msg.properties.retain(|item| item.0 != Property::SubscriptionIdentifier);
+        msg.properties.extend(identifiers.iter().map(|identifier| PropertyItem(Property::SubscriptionIdentifier, PropertyValue::Long(*identifier))));
+        msg
+    }
+
+    ///
+    /// 发给 v5 订阅者的属性，带上剩余的有效时间
+    ///
+    fn outbound_properties(&self) -> Vec<PropertyItem> {
+        let mut properties = self.properties.clone();
+        if let Some(remaining) = self.remaining_expiry() {
+            properties.push(PropertyItem(Property::MessageExpiryInterval, PropertyValue::Long(remaining)));
+        }
+        properties
+    }
+
+    ///
+    /// 按订阅授予的 QoS 与保留标志生成转发的消息
+    ///
+    pub fn forward(&self, qos: MqttQos, retain: MqttRetain) -> ApplicationMessage {
+        ApplicationMessage {
+            qos: std::cmp::min(self.qos, qos),
+            retain,
+            ..self.clone()
+        }
+    }
+
+    pub fn to_v3(&self, message_id: u16, dup: MqttDup) -> v3::PublishMessage {
+        v3::PublishMessage::new(self.qos, dup, self.retain, self.topic.clone(), message_id, self.payload.clone())
+    }
+
+    pub fn to_v5(&self, message_id: u16, dup: MqttDup) -> v5::PublishMessage {
+        v5::PublishMessage::new(self.qos, dup, self.retain, self.topic.clone(), message_id, self.payload.clone(), Some(self.outbound_properties()))
+    }
+
+    ///
+    /// 使用主题别名打包 v5 PUBLISH，客户端已经知道该别名时省略主题名
+    ///
+    pub fn to_v5_with_alias(&self, message_id: u16, dup: MqttDup, alias: u16, known: bool) -> v5::PublishMessage {
+        let mut properties = self.outbound_properties();
+        properties.push(PropertyItem(Property::TopicAlias, PropertyValue::Short(alias)));
+        let topic = if known { String::new() } else { self.topic.clone() };
+        v5::PublishMessage::new(self.qos, dup, self.retain, topic, message_id, self.payload.clone(), Some(properties))
+    }
+
+    ///
+    /// 按订阅者的协议版本打包成 PUBLISH 报文
+    ///
+    pub fn encode(&self, protocol_level: MqttProtocolLevel, message_id: u16, dup: MqttDup) -> Vec<u8> {
+        match protocol_level {
+            MqttProtocolLevel::Level5 => self.to_v5(message_id, dup).into_vec(),
+            _ => self.to_v3(message_id, dup).into_vec(),
+        }
+    }
+}
+
+///
+/// 只在发布者与服务端之间有效的属性不会转发给订阅者，有效时间在发送时重新计算
+///
+fn is_forwarded(item: &PropertyItem) -> bool {
+    !matches!(item.0, Property::TopicAlias | Property::SubscriptionIdentifier | Property::MessageExpiryInterval)
+}
+
+impl From<&v3::PublishMessage> for ApplicationMessage {
+    fn from(msg: &v3::PublishMessage) -> Self {
+        ApplicationMessage::new(msg.topic.clone(), msg.msg_body.clone(), msg.qos, msg.retain)
+    }
+}
+
+impl From<&v5::PublishMessage> for ApplicationMessage {
+    fn from(msg: &v5::PublishMessage) -> Self {
+        ApplicationMessage::new(msg.topic.clone(), msg.msg_body.clone(), msg.qos, msg.retain)
+            .with_properties(msg.properties.clone().unwrap_or_default())
+    }
+}
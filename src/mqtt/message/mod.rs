@@ -1,5 +1,5 @@
 use crate::mqtt::tools::types::TypeKind;
-use crate::mqtt::tools::un_pack_tool::{get_type, get_protocol_name_and_version};
+use crate::mqtt::tools::un_pack_tool::{get_type, get_protocol_name_and_version, get_remaining_data};
 use crate::mqtt::message::v3::{
     ConnackMessage, ConnectMessage, DisconnectMessage, MqttMessageV3,
     PubackMessage, PubcompMessage, PublishMessage, PubrecMessage, PubrelMessage,
@@ -10,9 +10,12 @@ use crate::mqtt::hex::PropertyItem;
 use crate::mqtt::tools::pack_tool::pack_header;
 use crate::mqtt::packet::v3_unpacket;
 use crate::mqtt::message::v5::MqttMessageV5;
+use crate::mqtt::tools::error::DecodeError;
+use std::convert::TryFrom;
 
 pub mod v3;
 pub mod v5;
+pub mod application;
 
 #[derive(Debug)]
 pub enum MqttMessageKind {
@@ -41,6 +44,10 @@ impl MqttMessageKind {
         matches!(self, MqttMessageKind::RequestsV5(_))
     }
 
+    pub fn is_exit(&self) -> bool {
+        matches!(self, MqttMessageKind::Exit(_))
+    }
+
     pub fn get_v3(&self) -> Option<&MqttMessageV3> {
         match self {
             MqttMessageKind::RequestV3(kind) => {
@@ -79,93 +86,85 @@ impl MqttMessageKind {
 }
 
 impl MqttMessageKind {
-    pub fn v3(base_msg: BaseMessage) -> Option<MqttMessageKind> {
-        match base_msg.get_message_type() {
-            TypeKind::CONNECT => { Some(Self::RequestV3(MqttMessageV3::Connect(ConnectMessage::from(base_msg)))) }
-            TypeKind::CONNACK => { Some(Self::RequestV3(MqttMessageV3::Connack(ConnackMessage::from(base_msg)))) }
-            TypeKind::PUBLISH => { Some(Self::RequestV3(MqttMessageV3::Publish(PublishMessage::from(base_msg)))) }
-            TypeKind::PUBACK => { Some(Self::RequestV3(MqttMessageV3::Puback(PubackMessage::from(base_msg)))) }
-            TypeKind::PUBREC => { Some(Self::RequestV3(MqttMessageV3::Pubrec(PubrecMessage::from(base_msg)))) }
-            TypeKind::PUBREL => { Some(Self::RequestV3(MqttMessageV3::Pubrel(PubrelMessage::from(base_msg)))) }
-            TypeKind::PUBCOMP => { Some(Self::RequestV3(MqttMessageV3::Pubcomp(PubcompMessage::from(base_msg)))) }
+    pub fn v3(base_msg: BaseMessage) -> Result<Option<MqttMessageKind>, DecodeError> {
+        let kind = match base_msg.get_message_type() {
+            TypeKind::CONNECT => { Self::RequestV3(MqttMessageV3::Connect(ConnectMessage::try_from(base_msg)?)) }
+            TypeKind::CONNACK => { Self::RequestV3(MqttMessageV3::Connack(ConnackMessage::try_from(base_msg)?)) }
+            TypeKind::PUBLISH => { Self::RequestV3(MqttMessageV3::Publish(PublishMessage::try_from(base_msg)?)) }
+            TypeKind::PUBACK => { Self::RequestV3(MqttMessageV3::Puback(PubackMessage::try_from(base_msg)?)) }
+            TypeKind::PUBREC => { Self::RequestV3(MqttMessageV3::Pubrec(PubrecMessage::try_from(base_msg)?)) }
+            TypeKind::PUBREL => { Self::RequestV3(MqttMessageV3::Pubrel(PubrelMessage::try_from(base_msg)?)) }
+            TypeKind::PUBCOMP => { Self::RequestV3(MqttMessageV3::Pubcomp(PubcompMessage::try_from(base_msg)?)) }
             TypeKind::SUBSCRIBE => {
-                let subs = v3_unpacket::subscribe(base_msg);
+                let subs = v3_unpacket::subscribe(base_msg)?;
                 let res = subs.into_iter()
-                    .map(|x| MqttMessageV3::Subscribe(x))
+                    .map(MqttMessageV3::Subscribe)
                     .collect::<Vec<MqttMessageV3>>();
-                Some(Self::RequestsV3(res))
+                Self::RequestsV3(res)
             }
             // TypeKind::SUBACK => { Some(Self::RequestV3(MqttMessageV3::Suback(SubackMessage::from(base_msg)))) }
             TypeKind::UNSUBSCRIBE => {
-                let subs = v3_unpacket::unsubscribe(base_msg);
+                let subs = v3_unpacket::unsubscribe(base_msg)?;
                 let res = subs.into_iter()
-                    .map(|x| MqttMessageV3::Unsubscribe(x))
+                    .map(MqttMessageV3::Unsubscribe)
                     .collect::<Vec<MqttMessageV3>>();
-                Some(Self::RequestsV3(res))
+                Self::RequestsV3(res)
             }
-            TypeKind::UNSUBACK => { Some(Self::RequestV3(MqttMessageV3::Unsuback(UnsubackMessage::from(base_msg)))) }
-            TypeKind::PINGREQ => { Some(Self::RequestV3(MqttMessageV3::Pingresp(PingrespMessage::default()))) }
-            TypeKind::DISCONNECT => { Some(Self::RequestV3(MqttMessageV3::Disconnect(DisconnectMessage::default()))) }
+            TypeKind::UNSUBACK => { Self::RequestV3(MqttMessageV3::Unsuback(UnsubackMessage::try_from(base_msg)?)) }
+            TypeKind::PINGREQ => { Self::RequestV3(MqttMessageV3::Pingresp(PingrespMessage::default())) }
+            TypeKind::DISCONNECT => { Self::RequestV3(MqttMessageV3::Disconnect(DisconnectMessage::default())) }
             // TypeKind::AUTH => { None }
-            _ => { None }
-        }
+            _ => { return Ok(None); }
+        };
+        Ok(Some(kind))
     }
 }
 
 impl MqttMessageKind {
-    pub fn v5(base_msg: BaseMessage) -> Option<MqttMessageKind> {
-        match base_msg.msg_type {
+    pub fn v5(base_msg: BaseMessage) -> Result<Option<MqttMessageKind>, DecodeError> {
+        let kind = match base_msg.msg_type {
             TypeKind::CONNECT => {
-                Some(
-                    Self::RequestV5(MqttMessageV5::Connect(crate::mqtt::message::v5::ConnectMessage::from(base_msg)))
-                )
+                Self::RequestV5(MqttMessageV5::Connect(crate::mqtt::message::v5::ConnectMessage::try_from(base_msg)?))
             }
             // TypeKind::CONNACK => {}
             TypeKind::PUBLISH => {
-                Some(Self::RequestV5(MqttMessageV5::Publish(crate::mqtt::message::v5::PublishMessage::from(base_msg))))
+                Self::RequestV5(MqttMessageV5::Publish(crate::mqtt::message::v5::PublishMessage::try_from(base_msg)?))
             }
             TypeKind::PUBACK => {
-                Some(
-                    Self::RequestV5(MqttMessageV5::Puback(crate::mqtt::message::v5::CommonPayloadMessage::from(base_msg)))
-                )
+                Self::RequestV5(MqttMessageV5::Puback(crate::mqtt::message::v5::CommonPayloadMessage::try_from(base_msg)?))
             }
             TypeKind::PUBREC => {
-                Some(
-                    Self::RequestV5(MqttMessageV5::Pubrec(crate::mqtt::message::v5::CommonPayloadMessage::from(base_msg)))
-                )
+                Self::RequestV5(MqttMessageV5::Pubrec(crate::mqtt::message::v5::CommonPayloadMessage::try_from(base_msg)?))
             }
             TypeKind::PUBREL => {
-                Some(
-                    Self::RequestV5(MqttMessageV5::Pubrel(crate::mqtt::message::v5::CommonPayloadMessage::from(base_msg)))
-                )
+                Self::RequestV5(MqttMessageV5::Pubrel(crate::mqtt::message::v5::CommonPayloadMessage::try_from(base_msg)?))
             }
             TypeKind::PUBCOMP => {
-                Some(
-                    Self::RequestV5(MqttMessageV5::Pubcomp(crate::mqtt::message::v5::CommonPayloadMessage::from(base_msg)))
-                )
+                Self::RequestV5(MqttMessageV5::Pubcomp(crate::mqtt::message::v5::CommonPayloadMessage::try_from(base_msg)?))
             }
             TypeKind::SUBSCRIBE => {
-                let subs = crate::mqtt::packet::v5_unpacket::subscribe(base_msg);
+                let subs = crate::mqtt::packet::v5_unpacket::subscribe(base_msg)?;
                 let res = subs.into_iter()
-                    .map(|x| MqttMessageV5::Subscribe(x))
+                    .map(MqttMessageV5::Subscribe)
                     .collect::<Vec<MqttMessageV5>>();
-                Some(Self::RequestsV5(res))
+                Self::RequestsV5(res)
             }
             // TypeKind::SUBACK => {}
             TypeKind::UNSUBSCRIBE => {
-                let subs = crate::mqtt::packet::v5_unpacket::unsubscribe(base_msg);
+                let subs = crate::mqtt::packet::v5_unpacket::unsubscribe(base_msg)?;
                 let res = subs.into_iter()
-                    .map(|x| MqttMessageV5::Unsubscribe(x))
+                    .map(MqttMessageV5::Unsubscribe)
                     .collect::<Vec<MqttMessageV5>>();
-                Some(Self::RequestsV5(res))
+                Self::RequestsV5(res)
             }
             // TypeKind::UNSUBACK => {}
-            TypeKind::PINGREQ => { Some(Self::RequestV5(MqttMessageV5::Pingresp(PingrespMessage::default()))) }
+            TypeKind::PINGREQ => { Self::RequestV5(MqttMessageV5::Pingresp(PingrespMessage::default())) }
             // TypeKind::PINGRESP => {}
-            TypeKind::DISCONNECT => { Some(Self::RequestV5(MqttMessageV5::Disconnect(crate::mqtt::message::v5::DisconnectMessage::default()))) }
-            TypeKind::AUTH => { Some(Self::RequestV5(MqttMessageV5::Auth(crate::mqtt::message::v5::AuthMessage::default()))) }
-            _ => { None }
-        }
+            TypeKind::DISCONNECT => { Self::RequestV5(MqttMessageV5::Disconnect(crate::mqtt::packet::v5_unpacket::disconnect(base_msg)?)) }
+            TypeKind::AUTH => { Self::RequestV5(MqttMessageV5::Auth(crate::mqtt::message::v5::AuthMessage::try_from(base_msg)?)) }
+            _ => { return Ok(None); }
+        };
+        Ok(Some(kind))
     }
 }
 
@@ -194,17 +193,21 @@ impl MqttMessage for BaseMessage {
     }
 }
 
-impl From<Vec<u8>> for BaseMessage {
-    fn from(data: Vec<u8>) -> Self {
-        let (r#type2, retain, qos, dup, _last_bytes) = get_type(data.as_slice());
-        BaseMessage { msg_type: r#type2.unwrap(), dup, qos, retain, bytes: data }
+impl TryFrom<Vec<u8>> for BaseMessage {
+    type Error = DecodeError;
+
+    fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        let (r#type2, retain, qos, dup, _last_bytes) = get_type(data.as_slice())?;
+        Ok(BaseMessage { msg_type: r#type2, dup, qos, retain, bytes: data })
     }
 }
 
-impl From<&[u8]> for BaseMessage {
-    fn from(data: &[u8]) -> Self {
-        let (r#type2, retain, qos, dup, _last_bytes) = get_type(data);
-        BaseMessage { msg_type: r#type2.unwrap(), dup, qos, retain, bytes: data.to_vec() }
+impl TryFrom<&[u8]> for BaseMessage {
+    type Error = DecodeError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let (r#type2, retain, qos, dup, _last_bytes) = get_type(data)?;
+        Ok(BaseMessage { msg_type: r#type2, dup, qos, retain, bytes: data.to_vec() })
     }
 }
 
@@ -229,18 +232,20 @@ impl BaseConnect {
     }
 }
 
-impl From<&BaseMessage> for BaseConnect {
-    fn from(data: &BaseMessage) -> Self {
-        let message_bytes = data.bytes.get(2..).unwrap();
+impl TryFrom<&BaseMessage> for BaseConnect {
+    type Error = DecodeError;
+
+    fn try_from(data: &BaseMessage) -> Result<Self, Self::Error> {
+        let message_bytes = get_remaining_data(data.bytes.as_slice())?;
         let (
             protocol_name,
             protocol_level
-        ) = get_protocol_name_and_version(message_bytes);
-        BaseConnect {
+        ) = get_protocol_name_and_version(message_bytes)?;
+        Ok(BaseConnect {
             msg_type: data.msg_type,
-            protocol_name: protocol_name.unwrap(),
-            protocol_level: protocol_level.unwrap(),
-        }
+            protocol_name,
+            protocol_level,
+        })
     }
 }
 
@@ -248,9 +253,9 @@ impl From<&BaseMessage> for BaseConnect {
 pub struct ConnectMessagePayload {
     pub client_id: String,
     pub will_topic: Option<String>,
-    pub will_message: Option<String>,
+    pub will_message: Option<Vec<u8>>,
     pub user_name: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<Vec<u8>>,
     pub properties: Option<Vec<PropertyItem>>,
 }
 
@@ -329,9 +334,17 @@ impl Default for PingrespMessage {
     }
 }
 
+///
+/// 载荷是合法的 UTF-8 时以文本返回
+///
+pub fn body_as_str(body: &[u8]) -> Option<&str> {
+    std::str::from_utf8(body).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mqtt::hex::reason_code::ReasonPhrases;
 
     #[test]
     fn test() {
@@ -353,8 +366,70 @@ mod tests {
             116, 112, 115, 58, 47, 47,
             99, 110, 46, 98, 105, 110,
             103, 46, 99, 111, 109, 34, 125];
-        let base_msg: BaseMessage = binary.as_ref().into();
-        let msg: PublishMessage = base_msg.into();
+        let base_msg = BaseMessage::try_from(binary.as_ref()).unwrap();
+        let msg = PublishMessage::try_from(base_msg).unwrap();
         println!("{:?}", msg);
     }
+
+    #[test]
+    fn test_decode_error() {
+        // 报文种类为 0
+        assert_eq!(BaseMessage::try_from(vec![0, 0]).err(), Some(DecodeError::UnknownType(0)));
+        // 主题长度超出报文
+        let base_msg = BaseMessage::try_from(vec![48, 4, 0, 9, 97, 98]).unwrap();
+        assert_eq!(PublishMessage::try_from(base_msg).err(), Some(DecodeError::Truncated));
+        // 订阅报文缺少 QoS
+        let base_msg = BaseMessage::try_from(vec![130, 5, 0, 1, 0, 1, 97]).unwrap();
+        assert_eq!(MqttMessageKind::v3(base_msg).err(), Some(DecodeError::Truncated));
+        // 订阅请求的 QoS 不能是表示失败的 0x80
+        let base_msg = BaseMessage::try_from(vec![130, 6, 0, 1, 0, 1, 97, 128]).unwrap();
+        assert_eq!(MqttMessageKind::v3(base_msg).err(), Some(DecodeError::InvalidQos(128)));
+    }
+
+    #[test]
+    fn test_binary_payload() {
+        let body = vec![0x00, 0xff, 0xfe, 0x80];
+        let msg = PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Disable, "image".to_string(), 7, body.clone());
+        let base_msg = BaseMessage::try_from(msg.into_vec()).unwrap();
+        let msg = PublishMessage::try_from(base_msg).unwrap();
+        assert_eq!(msg.msg_body, body);
+        assert_eq!(body_as_str(&msg.msg_body), None);
+
+        // 载荷格式指示为 UTF-8，但载荷不是合法的 UTF-8
+        let base_msg = BaseMessage::try_from(vec![48, 7, 0, 1, 97, 2, 1, 1, 255]).unwrap();
+        assert!(v5::PublishMessage::try_from(base_msg).unwrap().is_payload_format_invalid());
+        let base_msg = BaseMessage::try_from(vec![48, 7, 0, 1, 97, 2, 1, 0, 255]).unwrap();
+        let msg = v5::PublishMessage::try_from(base_msg).unwrap();
+        assert!(!msg.is_payload_format_invalid());
+        assert_eq!(msg.msg_body, vec![255]);
+    }
+
+    #[test]
+    fn test_v5_publish() {
+        let msg = v5::PublishMessage::new(MqttQos::Qos1, MqttDup::Disable, MqttRetain::Enable, "a/b".to_string(), 3, b"hello".to_vec(), None);
+        let base_msg = BaseMessage::try_from(msg.into_vec()).unwrap();
+        let msg = v5::PublishMessage::try_from(base_msg).unwrap();
+        assert_eq!(msg.topic, "a/b");
+        assert_eq!(msg.message_id, 3);
+        assert_eq!(msg.retain, MqttRetain::Enable);
+        assert_eq!(msg.msg_body, b"hello".to_vec());
+
+        // 原因码在属性之前
+        let msg = v5::CommonPayloadMessage::with_code(TypeKind::PUBACK, 1, ReasonPhrases::NoMatchingSubscribers);
+        assert_eq!(msg.as_bytes(), &[0x40, 4, 0, 1, 0x10, 0]);
+        let msg = v5::CommonPayloadMessage::try_from(BaseMessage::try_from(msg.into_vec()).unwrap()).unwrap();
+        assert_eq!(msg.code.as_byte(), ReasonPhrases::NoMatchingSubscribers.as_byte());
+    }
+
+    #[test]
+    fn test_suback() {
+        let msg = v3::SubackMessage::from_codes(10, vec![MqttQos::Qos1, MqttQos::Failure, MqttQos::Qos0]);
+        assert_eq!(msg.as_bytes(), &[0x90, 5, 0, 10, 1, 0x80, 0]);
+
+        let msg = v5::SubackMessage::new(10, vec![2, 0x8F], None);
+        assert_eq!(msg.as_bytes(), &[0x90, 5, 0, 10, 0, 2, 0x8F]);
+
+        let msg = v5::UnsubackMessage::from_codes(10, vec![ReasonPhrases::Success, ReasonPhrases::NoSubscriptionExisted]);
+        assert_eq!(msg.as_bytes(), &[0xB0, 5, 0, 10, 0, 0, 0x11]);
+    }
 }
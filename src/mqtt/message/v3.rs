@@ -1,4 +1,6 @@
 use crate::mqtt::tools::types::TypeKind;
+use crate::mqtt::tools::error::DecodeError;
+use std::convert::TryFrom;
 use crate::mqtt::tools::protocol::{MqttProtocolLevel, MqttCleanSession, MqttWillFlag, MqttUsernameFlag, MqttPasswordFlag, MqttSessionPresent, MqttDup, MqttQos, MqttRetain};
 use crate::mqtt::hex::reason_code::{ReasonCodeV3};
 use crate::mqtt::tools::pack_tool::{pack_header};
@@ -147,9 +149,9 @@ impl ConnectMessage {
             payload: ConnectMessagePayload {
                 client_id: config.client_id(),
                 will_topic: config.will().will_topic(),
-                will_message: config.will().will_message(),
+                will_message: config.will().will_message().map(String::into_bytes),
                 user_name: config.username(),
-                password: config.password(),
+                password: config.password().map(String::into_bytes),
                 properties: None,
             },
             bytes: None,
@@ -170,8 +172,10 @@ impl MqttBytesMessage for ConnectMessage {
     }
 }
 
-impl From<BaseMessage> for ConnectMessage {
-    fn from(data: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for ConnectMessage {
+    type Error = DecodeError;
+
+    fn try_from(data: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::connect(data)
     }
 }
@@ -211,8 +215,10 @@ impl Default for ConnackMessage {
     }
 }
 
-impl From<BaseMessage> for ConnackMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for ConnackMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::connack(base)
     }
 }
@@ -307,6 +313,20 @@ impl SubackMessage {
         msg.bytes = Some(v3_packet::suback(&msg));
         msg
     }
+
+    ///
+    /// 一个 SUBSCRIBE 报文对应一个 SUBACK，每个主题过滤器一个返回码
+    ///
+    pub fn from_codes(message_id: u16, codes: Vec<MqttQos>) -> Self {
+        let mut msg = SubackMessage {
+            msg_type: TypeKind::SUBACK,
+            message_id,
+            codes: codes.into_iter().map(|qos| qos.as_byte()).collect::<Vec<u8>>(),
+            bytes: None,
+        };
+        msg.bytes = Some(v3_packet::suback(&msg));
+        msg
+    }
 }
 
 impl From<SubscribeMessage> for SubackMessage {
@@ -399,8 +419,10 @@ impl UnsubackMessage {
     }
 }
 
-impl From<BaseMessage> for UnsubackMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for UnsubackMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::unsuback(base)
     }
 }
@@ -413,7 +435,7 @@ pub struct PublishMessage {
     pub dup: MqttDup,
     pub qos: MqttQos,
     pub retain: MqttRetain,
-    pub msg_body: String,
+    pub msg_body: Vec<u8>,
     pub bytes: Option<Vec<u8>>,
 }
 
@@ -433,14 +455,16 @@ impl MqttBytesMessage for PublishMessage {
     }
 }
 
-impl From<BaseMessage> for PublishMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for PublishMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::publish(base)
     }
 }
 
 impl PublishMessage {
-    pub fn new(qos: MqttQos, dup: MqttDup, retain: MqttRetain, topic: String, message_id: u16, message_body: String) -> PublishMessage {
+    pub fn new(qos: MqttQos, dup: MqttDup, retain: MqttRetain, topic: String, message_id: u16, message_body: Vec<u8>) -> PublishMessage {
         let mut msg = PublishMessage {
             msg_type: TypeKind::PUBLISH,
             message_id,
@@ -455,7 +479,21 @@ impl PublishMessage {
         msg
     }
 
-    pub fn simple_new_msg(topic: String, message_id: u16, message_body: String) -> PublishMessage {
+    ///
+    /// 按订阅授予的 QoS 与保留标志重新打包，用于转发给订阅者
+    ///
+    pub fn forward(&self, qos: MqttQos, retain: MqttRetain) -> PublishMessage {
+        PublishMessage::new(
+            std::cmp::min(self.qos, qos),
+            MqttDup::Disable,
+            retain,
+            self.topic.clone(),
+            self.message_id,
+            self.msg_body.clone(),
+        )
+    }
+
+    pub fn simple_new_msg(topic: String, message_id: u16, message_body: Vec<u8>) -> PublishMessage {
         PublishMessage::new(
             MqttQos::Qos1,
             MqttDup::Disable,
@@ -502,8 +540,10 @@ impl PubackMessage {
     }
 }
 
-impl From<BaseMessage> for PubackMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for PubackMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::puback(base)
     }
 }
@@ -543,8 +583,10 @@ impl PubrecMessage {
     }
 }
 
-impl From<BaseMessage> for PubrecMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for PubrecMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::pubrec(base)
     }
 }
@@ -584,8 +626,10 @@ impl PubrelMessage {
     }
 }
 
-impl From<BaseMessage> for PubrelMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for PubrelMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::pubrel(base)
     }
 }
@@ -625,8 +669,10 @@ impl PubcompMessage {
     }
 }
 
-impl From<BaseMessage> for PubcompMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for PubcompMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v3_unpacket::pubcomp(base)
     }
 }
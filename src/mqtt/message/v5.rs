@@ -1,7 +1,9 @@
 use crate::mqtt::tools::types::TypeKind;
+use crate::mqtt::tools::error::DecodeError;
+use std::convert::TryFrom;
 use crate::mqtt::tools::protocol::{MqttProtocolLevel, MqttCleanSession, MqttWillFlag, MqttQos, MqttRetain, MqttSessionPresent, MqttDup, MqttRetainAsPublished, MqttNoLocal};
 use crate::mqtt::hex::{PropertyItem, Property, PropertyValue};
-use crate::mqtt::message::{ConnectMessagePayload, BaseMessage, MqttMessage, MqttBytesMessage, PingreqMessage, PingrespMessage};
+use crate::mqtt::message::{ConnectMessagePayload, BaseMessage, MqttMessage, MqttBytesMessage, PingreqMessage, PingrespMessage, body_as_str};
 use crate::mqtt::packet::{v5_packet, v5_unpacket};
 use crate::mqtt::hex::reason_code::{ReasonPhrases, ReasonCodeV5};
 
@@ -43,8 +45,10 @@ pub struct ConnectMessage {
     pub bytes: Option<Vec<u8>>,
 }
 
-impl From<BaseMessage> for ConnectMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for ConnectMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::connect(base)
     }
 }
@@ -90,24 +94,17 @@ impl MqttBytesMessage for ConnackMessage {
     }
 }
 
-impl From<BaseMessage> for ConnackMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for ConnackMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::connack(base)
     }
 }
 
 impl Default for ConnackMessage {
     fn default() -> Self {
-        let properties = Some(
-            vec![
-                PropertyItem(Property::MaximumPacketSize, PropertyValue::Long(1048576)),
-                PropertyItem(Property::RetainAvailable, PropertyValue::Byte(1)),
-                PropertyItem(Property::SharedSubscriptionAvailable, PropertyValue::Byte(1)),
-                PropertyItem(Property::SubscriptionIdentifierAvailable, PropertyValue::Byte(1)),
-                PropertyItem(Property::TopicAliasMaximum, PropertyValue::Short(65535)),
-                PropertyItem(Property::WildcardSubscriptionAvailable, PropertyValue::Byte(1)),
-            ]
-        );
+        let properties = Some(ConnackMessage::server_properties(u16::MAX, 1048576));
         let bytes = v5_packet::connack(
             MqttSessionPresent::Disable,
             ReasonCodeV5::ReasonPhrases(ReasonPhrases::Success),
@@ -123,6 +120,38 @@ impl Default for ConnackMessage {
     }
 }
 
+impl ConnackMessage {
+    ///
+    /// 服务端支持的能力，连接成功时随 CONNACK 告知客户端
+    ///
+    pub fn server_properties(topic_alias_maximum: u16, maximum_packet_size: u32) -> Vec<PropertyItem> {
+        vec![
+            PropertyItem(Property::MaximumPacketSize, PropertyValue::Long(maximum_packet_size)),
+            PropertyItem(Property::RetainAvailable, PropertyValue::Byte(1)),
+            PropertyItem(Property::SharedSubscriptionAvailable, PropertyValue::Byte(1)),
+            PropertyItem(Property::SubscriptionIdentifierAvailable, PropertyValue::Byte(1)),
+            PropertyItem(Property::TopicAliasMaximum, PropertyValue::Short(topic_alias_maximum)),
+            PropertyItem(Property::WildcardSubscriptionAvailable, PropertyValue::Byte(1)),
+        ]
+    }
+
+    pub fn new(session_present: MqttSessionPresent, code: ReasonPhrases, properties: Option<Vec<PropertyItem>>) -> ConnackMessage {
+        let properties = if properties.is_some() { properties } else { Some(Vec::default()) };
+        let bytes = v5_packet::connack(
+            session_present,
+            ReasonCodeV5::ReasonPhrases(code),
+            properties.as_ref(),
+        );
+        ConnackMessage {
+            msg_type: TypeKind::CONNACK,
+            session_present,
+            return_code: code.as_byte(),
+            properties,
+            bytes,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PublishMessage {
     pub msg_type: TypeKind,
@@ -131,7 +160,7 @@ pub struct PublishMessage {
     pub dup: MqttDup,
     pub qos: MqttQos,
     pub retain: MqttRetain,
-    pub msg_body: String,
+    pub msg_body: Vec<u8>,
     pub properties: Option<Vec<PropertyItem>>,
     pub bytes: Option<Vec<u8>>,
 }
@@ -152,12 +181,41 @@ impl MqttBytesMessage for PublishMessage {
     }
 }
 
-impl From<BaseMessage> for PublishMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for PublishMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::publish(base)
     }
 }
 
+impl PublishMessage {
+    pub fn new(qos: MqttQos, dup: MqttDup, retain: MqttRetain, topic: String, message_id: u16, message_body: Vec<u8>, properties: Option<Vec<PropertyItem>>) -> PublishMessage {
+        let mut msg = PublishMessage {
+            msg_type: TypeKind::PUBLISH,
+            message_id,
+            topic,
+            dup,
+            qos,
+            retain,
+            msg_body: message_body,
+            properties: Some(properties.unwrap_or_default()),
+            bytes: None,
+        };
+        msg.bytes = Some(v5_packet::publish(&msg));
+        msg
+    }
+
+    ///
+    /// 载荷格式指示为 UTF-8，但载荷不是合法的 UTF-8
+    ///
+    pub fn is_payload_format_invalid(&self) -> bool {
+        let is_utf8 = self.properties.iter().flatten()
+            .any(|item| item.0 == Property::PayloadFormatIndicator && item.as_byte() == Some(1));
+        is_utf8 && body_as_str(&self.msg_body).is_none()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SubscribeMessage {
     pub msg_type: TypeKind,
@@ -237,12 +295,31 @@ impl MqttBytesMessage for SubackMessage {
     }
 }
 
-impl From<BaseMessage> for SubackMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for SubackMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::suback(base)
     }
 }
 
+impl SubackMessage {
+    ///
+    /// 一个 SUBSCRIBE 报文对应一个 SUBACK，每个主题过滤器一个原因码
+    ///
+    pub fn new(message_id: u16, codes: Vec<u8>, properties: Option<Vec<PropertyItem>>) -> Self {
+        let mut msg = SubackMessage {
+            msg_type: TypeKind::SUBACK,
+            message_id,
+            codes,
+            properties: Some(properties.unwrap_or_default()),
+            bytes: None,
+        };
+        msg.bytes = Some(v5_packet::suback(&msg));
+        msg
+    }
+}
+
 impl From<SubscribeMessage> for SubackMessage {
     fn from(smsg: SubscribeMessage) -> Self {
         let codes = if (smsg.qos.unwrap() as u32) < 3 {
@@ -287,8 +364,10 @@ impl MqttBytesMessage for UnsubackMessage {
     }
 }
 
-impl From<BaseMessage> for UnsubackMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for UnsubackMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::unsuback(base)
     }
 }
@@ -311,6 +390,21 @@ impl UnsubackMessage {
         msg.bytes = Some(v5_packet::unsuback(&msg));
         msg
     }
+
+    ///
+    /// 一个 UNSUBSCRIBE 报文对应一个 UNSUBACK，每个主题过滤器一个原因码
+    ///
+    pub fn from_codes(message_id: u16, codes: Vec<ReasonPhrases>) -> Self {
+        let mut msg = UnsubackMessage {
+            msg_type: TypeKind::UNSUBACK,
+            message_id,
+            codes: codes.into_iter().map(|code| code.as_byte()).collect::<Vec<u8>>(),
+            properties: Some(Vec::default()),
+            bytes: None,
+        };
+        msg.bytes = Some(v5_packet::unsuback(&msg));
+        msg
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -387,8 +481,10 @@ impl MqttBytesMessage for AuthMessage {
     }
 }
 
-impl From<BaseMessage> for AuthMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for AuthMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::auth(base)
     }
 }
@@ -417,10 +513,17 @@ pub struct CommonPayloadMessage {
 
 impl CommonPayloadMessage {
     pub fn new(kind: TypeKind, message_id: u16) -> CommonPayloadMessage {
+        CommonPayloadMessage::with_code(kind, message_id, ReasonPhrases::Success)
+    }
+
+    ///
+    /// 带原因码的 PUBACK、PUBREC、PUBREL、PUBCOMP
+    ///
+    pub fn with_code(kind: TypeKind, message_id: u16, code: ReasonPhrases) -> CommonPayloadMessage {
         let mut msg = CommonPayloadMessage {
             msg_type: kind,
             message_id,
-            code: ReasonPhrases::Success,
+            code,
             properties: Some(Vec::default()),
             bytes: None,
         };
@@ -445,8 +548,10 @@ impl MqttBytesMessage for CommonPayloadMessage {
     }
 }
 
-impl From<BaseMessage> for CommonPayloadMessage {
-    fn from(base: BaseMessage) -> Self {
+impl TryFrom<BaseMessage> for CommonPayloadMessage {
+    type Error = DecodeError;
+
+    fn try_from(base: BaseMessage) -> Result<Self, Self::Error> {
         v5_unpacket::get_reason_code(base)
     }
 }
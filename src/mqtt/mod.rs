@@ -1,10 +1,13 @@
-use crate::mqtt::v3_server::{Line, LineMessage};
+use crate::mqtt::v3_server::Line;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use crate::mqtt::message::MqttMessageKind;
-use log::{debug, info};
+use log::{debug, info, error};
+use tokio::time::{self, Instant};
 use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
 use std::str::FromStr;
+use std::time::Duration;
+use crate::mqtt::tools::framer::PacketFramer;
 
 use crate::CONFIG;
 
@@ -14,6 +17,10 @@ pub mod packet;
 pub mod message;
 pub mod v3_server;
 pub mod v3_handle;
+pub mod v5_handle;
+pub mod retain;
+pub mod session;
+pub mod client;
 
 pub struct MqttServer {
     addr: SocketAddr,
@@ -33,23 +40,60 @@ impl MqttServer {
 
             tokio::spawn(async move {
                 let mut buf = [0; 1024];
+                let mut framer = PacketFramer::new(CONFIG.get_mqtt_max_packet_size() as usize);
                 let mut line = Line::new();
+                let mut last_active = Instant::now();
+                let mut retry = time::interval(Duration::from_secs(CONFIG.get_mqtt_retry_interval()));
                 'end_loop: loop {
-                    let res = tokio::select! {
-                            Ok(n) = socket.read(&mut buf) => {
-                                if n != 0 {
-                                    line.get_sender().send(LineMessage::SocketMessage(buf[0..n].to_vec())).await.expect("send async bytes message error");
+                    let keep_alive = line.keep_alive_timeout();
+                    let kinds = tokio::select! {
+                            res = socket.read(&mut buf) => {
+                                let n = match res {
+                                    Ok(0) => {
+                                        info!("connection closed by peer");
+                                        break 'end_loop;
+                                    }
+                                    Ok(n) => n,
+                                    Err(e) => {
+                                        error!("failed to read from socket; err = {:?}", e);
+                                        break 'end_loop;
+                                    }
+                                };
+                                last_active = Instant::now();
+                                framer.extend(&buf[0..n]);
+                                let mut kinds = vec![];
+                                loop {
+                                    match framer.next_packet() {
+                                        Ok(Some(packet)) => {
+                                            if let Some(kind) = line.handle_socket_message(packet).await {
+                                                let is_exit = kind.is_exit();
+                                                kinds.push(kind);
+                                                if is_exit { break; }
+                                            }
+                                        }
+                                        Ok(None) => break,
+                                        Err(e) => {
+                                            kinds.push(line.handle_decode_error(e));
+                                            break;
+                                        }
+                                    }
                                 }
-                                None
+                                kinds
                             },
-                            kind = line.recv() => kind,
+                            kind = line.recv() => kind.into_iter().collect(),
+                            _ = retry.tick() => line.retry().into_iter().collect(),
+                            _ = time::sleep_until(last_active + keep_alive.unwrap_or_default()), if keep_alive.is_some() => {
+                                info!("keep alive timeout");
+                                break 'end_loop;
+                            }
                         };
-                    if let Some(kind) = res {
+                    for kind in kinds {
                         match kind {
                             MqttMessageKind::Response(data) => {
                                 debug!("data: {:?}", data);
                                 if let Err(e) = socket.write_all(data.as_slice()).await {
                                     debug!("failed to write to socket; err = {:?}", e);
+                                    break 'end_loop;
                                 }
                             }
                             MqttMessageKind::Exit(data) => {
@@ -62,6 +106,7 @@ impl MqttServer {
                         }
                     }
                 }
+                line.close().await;
             });
         }
     }
@@ -63,7 +63,7 @@ pub fn publish(msg: &PublishMessage) -> Vec<u8> {
         body.extend(pack_message_short_id(msg.message_id));
     }
 
-    body.extend(msg.msg_body.as_bytes().to_vec());
+    body.extend(msg.msg_body.as_slice());
 
     let mut package = pack_publish_header(msg.msg_type, body.len(), Option::from(msg.qos), Option::from(msg.dup), Option::from(msg.retain));
 
@@ -2,11 +2,12 @@ use crate::mqtt::tools::protocol::{MqttSessionPresent, MqttQos, MqttDup, MqttRet
 use crate::mqtt::message::v3::{ConnectMessage, PublishMessage, SubscribeMessage, SubackMessage, UnsubscribeMessage, ConnackMessage, UnsubackMessage, PubackMessage, PubrecMessage, PubrelMessage, PubcompMessage};
 use crate::mqtt::message::{BaseMessage};
 use crate::mqtt::tools::un_pack_tool::{get_connect_variable_header, get_connect_payload_data, parse_short_int, parse_string, parse_byte, get_remaining_data};
+use crate::mqtt::tools::error::DecodeError;
 use std::convert::TryFrom;
 
-pub fn connect(base: BaseMessage) -> ConnectMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (variable_header, last_data) = get_connect_variable_header(message_bytes);
+pub fn connect(base: BaseMessage) -> Result<ConnectMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (variable_header, last_data) = get_connect_variable_header(message_bytes)?;
 
     let payload = get_connect_payload_data(
         variable_header.protocol_level.unwrap(),
@@ -14,9 +15,9 @@ pub fn connect(base: BaseMessage) -> ConnectMessage {
         variable_header.will_flag.unwrap(),
         variable_header.username_flag.unwrap(),
         variable_header.password_flag.unwrap(),
-    );
+    )?;
 
-    ConnectMessage {
+    Ok(ConnectMessage {
         msg_type: base.msg_type,
         protocol_name: variable_header.protocol_name.unwrap(),
         protocol_level: variable_header.protocol_level.unwrap(),
@@ -27,140 +28,130 @@ pub fn connect(base: BaseMessage) -> ConnectMessage {
         keep_alive: variable_header.keep_alive.unwrap(),
         payload,
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn connack(base: BaseMessage) -> ConnackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let session_present = MqttSessionPresent::try_from(message_bytes.get(0).unwrap() & 1).unwrap();
-    let return_code = *message_bytes.get(1).unwrap();
-    ConnackMessage {
+pub fn connack(base: BaseMessage) -> Result<ConnackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (flags, last_data) = parse_byte(message_bytes)?;
+    let session_present = MqttSessionPresent::try_from(flags & 1).map_err(|_| DecodeError::ReservedFlags(flags))?;
+    let (return_code, _) = parse_byte(last_data)?;
+    Ok(ConnackMessage {
         msg_type: base.msg_type,
         session_present,
         return_code,
         bytes: base.bytes,
-    }
+    })
 }
 
-pub fn publish(base: BaseMessage) -> PublishMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (topic, last_data) = parse_string(message_bytes).unwrap();
-    let (message_id, msg_body) = if base.qos.is_some() {
-        let qos = base.qos.unwrap();
-        if qos > MqttQos::Qos0 {
-            let (message_id, last_data) = parse_short_int(last_data.unwrap());
-            let msg_body = String::from_utf8_lossy(last_data);
-            (message_id, msg_body)
-        } else {
-            let msg_body = String::from_utf8_lossy(last_data.unwrap());
-            (0, msg_body)
-        }
+pub fn publish(base: BaseMessage) -> Result<PublishMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (topic, last_data) = parse_string(message_bytes)?;
+    let qos = base.qos.unwrap_or(MqttQos::Qos0);
+    let (message_id, msg_body) = if qos > MqttQos::Qos0 {
+        let (message_id, last_data) = parse_short_int(last_data)?;
+        (message_id, last_data)
     } else {
-        let msg_body = String::from_utf8_lossy(last_data.unwrap());
-        (0, msg_body)
+        (0, last_data)
     };
 
-    PublishMessage {
+    Ok(PublishMessage {
         msg_type: base.msg_type,
         message_id,
         topic,
         dup: base.dup.unwrap_or(MqttDup::Disable),
-        qos: base.qos.unwrap_or(MqttQos::Qos0),
+        qos,
         retain: base.retain.unwrap_or(MqttRetain::Disable),
-        msg_body: msg_body.into_owned(),
+        msg_body: msg_body.to_vec(),
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn subscribe(base: BaseMessage) -> Vec<SubscribeMessage> {
+pub fn subscribe(base: BaseMessage) -> Result<Vec<SubscribeMessage>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, mut last_data) = parse_short_int(message_bytes)?;
     loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-        let (topic, last_data) = parse_string(last_data).unwrap();
-        let (qos, _) = parse_byte(last_data.unwrap());
+        let (topic, data) = parse_string(last_data)?;
+        let (qos, data) = parse_byte(data)?;
+        // 请求的 QoS 只能是 0~2，0x80 只用于 SUBACK 表示订阅失败
+        let qos = match qos {
+            0..=2 => MqttQos::try_from(qos).map_err(|_| DecodeError::InvalidQos(qos))?,
+            _ => return Err(DecodeError::InvalidQos(qos)),
+        };
         subs.push(
             SubscribeMessage {
                 msg_type: base.msg_type,
                 message_id,
                 topic,
-                qos: MqttQos::try_from(qos).unwrap(),
-                bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+                qos,
+                bytes: Some(base.bytes.clone()),
             }
         );
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
+        if last_data.is_empty() { break; }
     }
-    subs
+    Ok(subs)
 }
 
-pub fn unsubscribe(base: BaseMessage) -> Vec<UnsubscribeMessage> {
+pub fn unsubscribe(base: BaseMessage) -> Result<Vec<UnsubscribeMessage>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
-
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, mut last_data) = parse_short_int(message_bytes)?;
     loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-        let (topic, _last_data) = parse_string(last_data).unwrap();
+        let (topic, data) = parse_string(last_data)?;
         subs.push(
             UnsubscribeMessage {
                 msg_type: base.msg_type,
                 message_id,
                 topic,
-                bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+                bytes: Some(base.bytes.clone()),
             }
         );
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
+        if last_data.is_empty() { break; }
     }
-    subs
+    Ok(subs)
 }
 
-pub fn unsuback(base: BaseMessage) -> UnsubackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    UnsubackMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) }
+pub fn unsuback(base: BaseMessage) -> Result<UnsubackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(UnsubackMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) })
 }
 
-pub fn suback(base: BaseMessage) -> SubackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, last_data) = parse_short_int(message_bytes);
+pub fn suback(base: BaseMessage) -> Result<SubackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
     let codes = last_data.to_vec();
-    SubackMessage {
+    Ok(SubackMessage {
         msg_type: base.msg_type,
         message_id,
         codes,
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn puback(base: BaseMessage) -> PubackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    PubackMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) }
+pub fn puback(base: BaseMessage) -> Result<PubackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(PubackMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) })
 }
 
-pub fn pubrec(base: BaseMessage) -> PubrecMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    PubrecMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) }
+pub fn pubrec(base: BaseMessage) -> Result<PubrecMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(PubrecMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) })
 }
 
-pub fn pubrel(base: BaseMessage) -> PubrelMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    PubrelMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) }
+pub fn pubrel(base: BaseMessage) -> Result<PubrelMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(PubrelMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) })
 }
 
-pub fn pubcomp(base: BaseMessage) -> PubcompMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-    let (message_id, _) = parse_short_int(message_bytes);
-    PubcompMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) }
+pub fn pubcomp(base: BaseMessage) -> Result<PubcompMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, _) = parse_short_int(message_bytes)?;
+    Ok(PubcompMessage { msg_type: base.msg_type, message_id, bytes: Some(base.bytes) })
 }
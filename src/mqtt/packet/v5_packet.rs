@@ -1,5 +1,5 @@
 use crate::mqtt::message::v5::{ConnectMessage, SubackMessage, UnsubackMessage, DisconnectMessage, AuthMessage, SubscribeMessage, PublishMessage};
-use crate::mqtt::tools::pack_tool::{pack_connect_flags, pack_string, pack_short_int, pack_client_id, pack_header, pack_message_short_id, pack_publish_header};
+use crate::mqtt::tools::pack_tool::{pack_connect_flags, pack_string, pack_binary, pack_short_int, pack_client_id, pack_header, pack_message_short_id, pack_publish_header};
 use crate::mqtt::tools::protocol::{MqttWillFlag, MqttSessionPresent, MqttQos, MqttDup};
 use crate::mqtt::hex::{pack_property, PropertyItem};
 use crate::mqtt::tools::types::TypeKind;
@@ -38,7 +38,7 @@ pub fn connect(msg: &ConnectMessage) -> Vec<u8> {
             body.extend(will_topic);
         }
         if msg.payload.will_message.is_some() {
-            let will_message = pack_string(msg.payload.will_message.as_ref().unwrap());
+            let will_message = pack_binary(msg.payload.will_message.as_ref().unwrap());
             body.extend(will_message);
         }
     }
@@ -49,7 +49,7 @@ pub fn connect(msg: &ConnectMessage) -> Vec<u8> {
     }
 
     if msg.payload.password.is_some() {
-        let password = pack_string(msg.payload.password.as_ref().unwrap());
+        let password = pack_binary(msg.payload.password.as_ref().unwrap());
         body.extend(password);
     }
 
@@ -85,7 +85,7 @@ pub fn publish(msg: &PublishMessage) -> Vec<u8> {
         body.extend(pack_property::publish(msg.properties.as_ref().unwrap()));
     }
 
-    body.extend(msg.msg_body.as_bytes().to_vec());
+    body.extend(msg.msg_body.as_slice());
 
     let mut package = pack_publish_header(TypeKind::PUBLISH, body.len(), Option::from(msg.qos), Option::from(msg.dup), Option::from(msg.retain));
 
@@ -199,12 +199,12 @@ pub fn auth(msg: &AuthMessage) -> Vec<u8> {
 pub fn common(message_id: u16, code: ReasonPhrases, properties: Option<&Vec<PropertyItem>>, kind: TypeKind) -> Vec<u8> {
     let mut body = pack_message_short_id(message_id);
 
+    body.push(code.as_byte());
+
     if properties.is_some() {
         body.extend(pack_property::suback(properties.unwrap()));
     }
 
-    body.push(code.as_byte());
-
     let mut package = if kind.is_pubrel() {
         pack_publish_header(kind, body.len(), Option::from(MqttQos::Qos1), Option::from(MqttDup::Disable), None)
     } else {
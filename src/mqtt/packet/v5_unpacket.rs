@@ -1,26 +1,33 @@
 use crate::mqtt::message::BaseMessage;
 use crate::mqtt::message::v5::{ConnectMessage, ConnackMessage, PublishMessage, SubscribeMessage, SubackMessage, UnsubackMessage, UnsubscribeMessage, DisconnectMessage, AuthMessage, CommonPayloadMessage};
-use crate::mqtt::tools::un_pack_tool::{parse_short_int, parse_byte, parse_string, get_connect_variable_header, get_connect_payload_data, get_remaining_data};
-use crate::mqtt::hex::un_pack_property;
+use crate::mqtt::tools::un_pack_tool::{parse_short_int, parse_byte, parse_string, parse_var_int, get_connect_variable_header, get_connect_payload_data, get_remaining_data};
+use crate::mqtt::tools::error::DecodeError;
+use crate::mqtt::hex::{un_pack_property, PropertyItem};
 use crate::mqtt::tools::protocol::{MqttQos, MqttNoLocal, MqttRetainAsPublished, MqttSessionPresent, MqttDup, MqttRetain};
 use std::convert::TryFrom;
 use crate::mqtt::hex::reason_code::ReasonPhrases;
 
-pub fn connect(base: BaseMessage) -> ConnectMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+///
+/// 解析属性长度及属性列表，返回属性之后的数据
+///
+fn unpack_properties(data: &[u8], unpack: fn(u32, &[u8]) -> Result<Vec<PropertyItem>, DecodeError>) -> Result<(Vec<PropertyItem>, &[u8]), DecodeError> {
+    let (properties_total_length, last_data) = parse_var_int(data)?;
+    if properties_total_length > 0 {
+        Ok((
+            unpack(properties_total_length as u32, last_data)?,
+            last_data.get(properties_total_length..).ok_or(DecodeError::Truncated)?
+        ))
+    } else {
+        Ok((Vec::default(), last_data))
+    }
+}
 
-    let (variable_header, last_data) = get_connect_variable_header(message_bytes);
+pub fn connect(base: BaseMessage) -> Result<ConnectMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (variable_header, last_data) = get_connect_variable_header(message_bytes)?;
 
-    let (properties, last_data) = if properties_total_length > 0 {
-        (
-            Some(un_pack_property::connect(properties_total_length as u32, last_data)),
-            last_data.get(properties_total_length as usize..).unwrap()
-        )
-    } else {
-        (Some(Vec::default()), last_data)
-    };
+    let (properties, last_data) = unpack_properties(last_data, un_pack_property::connect)?;
 
     let payload = get_connect_payload_data(
         variable_header.protocol_level.unwrap(),
@@ -28,9 +35,9 @@ pub fn connect(base: BaseMessage) -> ConnectMessage {
         variable_header.will_flag.unwrap(),
         variable_header.username_flag.unwrap(),
         variable_header.password_flag.unwrap(),
-    );
+    )?;
 
-    ConnectMessage {
+    Ok(ConnectMessage {
         msg_type: base.msg_type,
         protocol_name: variable_header.protocol_name.unwrap(),
         protocol_level: variable_header.protocol_level.unwrap(),
@@ -39,311 +46,226 @@ pub fn connect(base: BaseMessage) -> ConnectMessage {
         will_qos: variable_header.will_qos.unwrap(),
         will_retain: variable_header.will_retain.unwrap(),
         keep_alive: variable_header.keep_alive.unwrap(),
-        properties,
+        properties: Some(properties),
         payload,
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn connack(base: BaseMessage) -> ConnackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn connack(base: BaseMessage) -> Result<ConnackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let session_present = MqttSessionPresent::try_from(message_bytes.get(0).unwrap() & 1).unwrap();
+    let (flags, last_data) = parse_byte(message_bytes)?;
 
-    let (return_code, last_data) = parse_byte(message_bytes);
+    let session_present = MqttSessionPresent::try_from(flags & 1).map_err(|_| DecodeError::ReservedFlags(flags))?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let (return_code, last_data) = parse_byte(last_data)?;
 
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::connack(properties_total_length as u32, last_data))
-    } else {
-        Some(Vec::default())
-    };
+    let (properties, _) = unpack_properties(last_data, un_pack_property::connack)?;
 
-    ConnackMessage {
+    Ok(ConnackMessage {
         msg_type: base.msg_type,
         session_present,
         return_code,
-        properties,
+        properties: Some(properties),
         bytes: base.bytes,
-    }
+    })
 }
 
-pub fn publish(base: BaseMessage) -> PublishMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
-
-    let (topic, last_data) = parse_string(message_bytes).unwrap();
+pub fn publish(base: BaseMessage) -> Result<PublishMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = if base.qos.is_some() {
-        let qos = base.qos.unwrap();
-        if qos > MqttQos::Qos0 {
-            let (message_id, last_data) = parse_short_int(last_data.unwrap());
-            (message_id, last_data)
-        } else {
-            (0, last_data.unwrap())
-        }
-    } else {
-        (0, last_data.unwrap())
-    };
+    let (topic, last_data) = parse_string(message_bytes)?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
+    let qos = base.qos.unwrap_or(MqttQos::Qos0);
 
-    let (properties, msg_body) = if properties_total_length > 0 {
-        let msg_body = String::from_utf8_lossy(last_data.get(properties_total_length as usize..).unwrap());
-        (
-            Some(un_pack_property::publish(properties_total_length as u32, last_data)),
-            msg_body
-        )
+    let (message_id, last_data) = if qos > MqttQos::Qos0 {
+        parse_short_int(last_data)?
     } else {
-        let msg_body = String::from_utf8_lossy(last_data);
-        (
-            Some(Vec::default()),
-            msg_body
-        )
+        (0, last_data)
     };
 
-    PublishMessage {
+    let (properties, last_data) = unpack_properties(last_data, un_pack_property::publish)?;
+
+    Ok(PublishMessage {
         msg_type: base.msg_type,
         message_id,
         topic,
         dup: base.dup.unwrap_or(MqttDup::Disable),
-        qos: base.qos.unwrap_or(MqttQos::Qos0),
+        qos,
         retain: base.retain.unwrap_or(MqttRetain::Disable),
-        msg_body: msg_body.into_owned(),
-        properties,
+        msg_body: last_data.to_vec(),
+        properties: Some(properties),
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn subscribe(base: BaseMessage) -> Vec<SubscribeMessage> {
+pub fn subscribe(base: BaseMessage) -> Result<Vec<SubscribeMessage>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
+    let (properties, mut last_data) = unpack_properties(last_data, un_pack_property::subscribe)?;
 
     loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-        let (properties_total_length, last_data) = parse_byte(last_data);
-        let (properties, last_data) = if properties_total_length > 0 {
-            (
-                Some(un_pack_property::subscribe(properties_total_length as u32, last_data)),
-                last_data.get(properties_total_length as usize..).unwrap()
-            )
-        } else {
-            (
-                Some(Vec::default()),
-                last_data
-            )
-        };
-
-        let (topic, last_data) = parse_string(last_data).unwrap();
-        let (byte_data, _) = parse_byte(last_data.unwrap());
+        let (topic, data) = parse_string(last_data)?;
+        let (byte_data, data) = parse_byte(data)?;
+        if byte_data >> 6 != 0 {
+            return Err(DecodeError::Malformed("subscription options reserved bits set"));
+        }
         let qos = byte_data & 3;
         let no_local = byte_data >> 2 & 1;
         let retain_as_published = byte_data >> 3 & 1;
-        let retain_handling = byte_data >> 4;
+        let retain_handling = byte_data >> 4 & 3;
+        if retain_handling > 2 {
+            return Err(DecodeError::Malformed("invalid retain handling"));
+        }
         subs.push(SubscribeMessage {
             msg_type: base.msg_type,
             message_id,
             topic,
-            qos: MqttQos::try_from(qos).ok(),
+            qos: Some(MqttQos::try_from(qos).map_err(|_| DecodeError::InvalidQos(qos))?),
             no_local: MqttNoLocal::try_from(no_local).ok(),
             retain_as_published: MqttRetainAsPublished::try_from(retain_as_published).ok(),
             retain_handling: Option::from(retain_handling),
-            properties,
-            bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+            properties: Some(properties.clone()),
+            bytes: Some(base.bytes.clone()),
         });
 
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
+        if last_data.is_empty() { break; }
     }
 
-    subs
+    Ok(subs)
 }
 
-pub fn unsubscribe(base: BaseMessage) -> Vec<UnsubscribeMessage> {
+pub fn unsubscribe(base: BaseMessage) -> Result<Vec<UnsubscribeMessage>, DecodeError> {
     let mut subs = vec![];
-    let mut data_bytes = base.bytes.as_slice();
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
+    let (properties, mut last_data) = unpack_properties(last_data, un_pack_property::unsubscribe)?;
 
     loop {
-        let remain_data = get_remaining_data(data_bytes);
-        let (message_id, last_data) = parse_short_int(remain_data);
-
-        let (properties_total_length, last_data) = parse_byte(last_data);
-        let (properties, last_data) = if properties_total_length > 0 {
-            (
-                Some(un_pack_property::unsubscribe(properties_total_length as u32, last_data)),
-                last_data.get(properties_total_length as usize..).unwrap()
-            )
-        } else {
-            (
-                Some(Vec::default()),
-                last_data
-            )
-        };
-
-        let (topic, _) = parse_string(last_data).unwrap();
+        let (topic, data) = parse_string(last_data)?;
 
         subs.push(UnsubscribeMessage {
             msg_type: base.msg_type,
             message_id,
             topic,
-            properties,
-            bytes: Some(data_bytes.get(..remain_data.len() + 2).unwrap().to_vec()),
+            properties: Some(properties.clone()),
+            bytes: Some(base.bytes.clone()),
         });
 
-        if let Some(last_data) = data_bytes.get(remain_data.len() + 2..) {
-            if last_data.len() > 0 { data_bytes = last_data; } else { break; }
-        } else {
-            break;
-        }
+        last_data = data;
+        if last_data.is_empty() { break; }
     }
 
-    subs
+    Ok(subs)
 }
 
-pub fn suback(base: BaseMessage) -> SubackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn suback(base: BaseMessage) -> Result<SubackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = parse_short_int(message_bytes);
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::suback(properties_total_length as u32, last_data))
-    } else {
-        Some(Vec::default())
-    };
+    let (properties, last_data) = unpack_properties(last_data, un_pack_property::suback)?;
 
     let codes = last_data.to_vec();
 
-    SubackMessage {
+    Ok(SubackMessage {
         msg_type: base.msg_type,
         message_id,
         codes,
-        properties,
+        properties: Some(properties),
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn unsuback(base: BaseMessage) -> UnsubackMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn unsuback(base: BaseMessage) -> Result<UnsubackMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = parse_short_int(message_bytes);
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
 
-    let (properties_total_length, last_data) = parse_byte(last_data);
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::suback(properties_total_length as u32, last_data))
-    } else {
-        Some(Vec::default())
-    };
+    let (properties, last_data) = unpack_properties(last_data, un_pack_property::unsuback)?;
 
     let codes = last_data.to_vec();
 
-    UnsubackMessage {
+    Ok(UnsubackMessage {
         msg_type: base.msg_type,
         message_id,
         codes,
-        properties,
+        properties: Some(properties),
         bytes: Some(base.bytes),
-    }
+    })
 }
 
-pub fn disconnect(base: BaseMessage) -> DisconnectMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn disconnect(base: BaseMessage) -> Result<DisconnectMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (code, mut last_data) = if message_bytes.len() > 0 {
-        parse_byte(message_bytes)
+    let (code, last_data) = if !message_bytes.is_empty() {
+        parse_byte(message_bytes)?
     } else {
         (ReasonPhrases::Success as u8, message_bytes)
     };
 
-    let mut properties_total_length = 0;
-
-    if last_data.len() > 0 {
-        let (length, last) = parse_byte(last_data);
-        properties_total_length = length;
-        last_data = last
-    }
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::suback(properties_total_length as u32, last_data))
+    let properties = if !last_data.is_empty() {
+        unpack_properties(last_data, un_pack_property::disconnect)?.0
     } else {
-        Some(Vec::default())
+        Vec::default()
     };
 
-    DisconnectMessage {
+    Ok(DisconnectMessage {
         msg_type: base.msg_type,
         code,
-        properties,
+        properties: Some(properties),
         bytes: base.bytes,
-    }
+    })
 }
 
-pub fn auth(base: BaseMessage) -> AuthMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn auth(base: BaseMessage) -> Result<AuthMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (code, mut last_data) = if message_bytes.len() > 0 {
-        parse_byte(message_bytes)
+    let (code, last_data) = if !message_bytes.is_empty() {
+        parse_byte(message_bytes)?
     } else {
         (ReasonPhrases::Success as u8, message_bytes)
     };
 
-    let mut properties_total_length = 0;
-
-    if last_data.len() > 0 {
-        let (length, last) = parse_byte(last_data);
-        properties_total_length = length;
-        last_data = last
-    }
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::auth(properties_total_length as u32, last_data))
+    let properties = if !last_data.is_empty() {
+        unpack_properties(last_data, un_pack_property::auth)?.0
     } else {
-        Some(Vec::default())
+        Vec::default()
     };
 
-    AuthMessage {
+    Ok(AuthMessage {
         msg_type: base.msg_type,
         code,
-        properties,
+        properties: Some(properties),
         bytes: base.bytes,
-    }
+    })
 }
 
-pub fn get_reason_code(base: BaseMessage) -> CommonPayloadMessage {
-    let message_bytes = base.bytes.get(2..).unwrap();
+pub fn get_reason_code(base: BaseMessage) -> Result<CommonPayloadMessage, DecodeError> {
+    let message_bytes = get_remaining_data(base.bytes.as_slice())?;
 
-    let (message_id, last_data) = parse_short_int(message_bytes);
+    let (message_id, last_data) = parse_short_int(message_bytes)?;
 
-    let (code, mut last_data) = if message_bytes.len() > 0 {
-        parse_byte(last_data)
+    let (code, last_data) = if !last_data.is_empty() {
+        parse_byte(last_data)?
     } else {
         (ReasonPhrases::Success as u8, last_data)
     };
 
-    let mut properties_total_length = 0;
-
-    if last_data.len() > 0 {
-        let (length, last) = parse_byte(last_data);
-        properties_total_length = length;
-        last_data = last
-    }
-
-    let properties = if properties_total_length > 0 {
-        Some(un_pack_property::pub_and_sub(properties_total_length as u32, last_data))
+    let properties = if !last_data.is_empty() {
+        unpack_properties(last_data, un_pack_property::pub_and_sub)?.0
     } else {
-        Some(Vec::default())
+        Vec::default()
     };
 
-    CommonPayloadMessage {
+    Ok(CommonPayloadMessage {
         msg_type: base.msg_type,
         message_id,
-        code: crate::mqtt::hex::reason_code::ReasonPhrases::try_from(code).unwrap(),
-        properties,
+        code: ReasonPhrases::try_from(code).map_err(|_| DecodeError::InvalidReasonCode(code))?,
+        properties: Some(properties),
         bytes: Some(base.bytes),
-    }
+    })
 }
@@ -0,0 +1,88 @@
+use crate::mqtt::v3_server::TopicMessage;
+use crate::mqtt::tools::topic;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+///
+/// 保留消息，每个主题只保存最后一条，过期的消息在读取时删除
+///
+pub struct Retain {
+    container: Arc<Mutex<HashMap<String, TopicMessage>>>,
+}
+
+impl Default for Retain {
+    fn default() -> Self {
+        Retain::new()
+    }
+}
+
+impl Retain {
+    pub fn new() -> Retain {
+        Retain { container: Arc::new(Mutex::new(HashMap::default())) }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.container.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.container.lock().await.is_empty()
+    }
+
+    pub async fn get<S: AsRef<str>>(&self, topic_name: S) -> Option<TopicMessage> {
+        let mut container = self.container.lock().await;
+        container.retain(|_, msg| !msg.is_expired());
+        container.get(topic_name.as_ref()).cloned()
+    }
+
+    pub async fn set<S: Into<String>>(&self, topic_name: S, msg: TopicMessage) -> Option<TopicMessage> {
+        self.container.lock().await.insert(topic_name.into(), msg)
+    }
+
+    pub async fn remove<S: AsRef<str>>(&self, topic_name: S) -> Option<TopicMessage> {
+        self.container.lock().await.remove(topic_name.as_ref())
+    }
+
+    ///
+    /// 返回主题名匹配订阅过滤器的保留消息
+    ///
+    pub async fn matches<S: AsRef<str>>(&self, filter: S) -> Vec<TopicMessage> {
+        let mut container = self.container.lock().await;
+        container.retain(|_, msg| !msg.is_expired());
+        container.iter()
+            .filter(|(topic_name, _)| topic::is_match(filter.as_ref(), topic_name))
+            .map(|(_, msg)| msg.clone())
+            .collect::<Vec<TopicMessage>>()
+    }
+
+    pub async fn messages(&self) -> HashMap<String, TopicMessage> {
+        let mut container = self.container.lock().await;
+        container.retain(|_, msg| !msg.is_expired());
+        container.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::message::application::ApplicationMessage;
+    use crate::mqtt::tools::protocol::{MqttQos, MqttRetain};
+    use crate::mqtt::v3_server::ClientID;
+
+    #[tokio::test]
+    async fn test() {
+        let retain = Retain::new();
+        let msg = ApplicationMessage::new("machines/1/status", b"online".to_vec(), MqttQos::Qos1, MqttRetain::Enable);
+        retain.set("machines/1/status", TopicMessage::Content(ClientID::from("1"), msg.clone())).await;
+        retain.set("machines/2/status", TopicMessage::Content(ClientID::from("2"), msg)).await;
+
+        assert_eq!(retain.len().await, 2);
+        assert_eq!(retain.matches("machines/+/status").await.len(), 2);
+        assert_eq!(retain.matches("machines/1/#").await.len(), 1);
+        assert!(retain.matches("machines/3/status").await.is_empty());
+
+        retain.remove("machines/1/status").await;
+        assert_eq!(retain.matches("machines/#").await.len(), 1);
+    }
+}
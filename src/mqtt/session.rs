@@ -0,0 +1,577 @@
+use crate::mqtt::message::v3::PubrelMessage;
+use crate::mqtt::message::MqttBytesMessage;
+use crate::mqtt::message::application::ApplicationMessage;
+use crate::mqtt::tools::topic_alias::OutboundAliases;
+use crate::mqtt::tools::protocol::{MqttQos, MqttDup, MqttProtocolLevel};
+use crate::mqtt::v3_server::{ClientID, TopicMessage, SubscriptionOptions};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use log::warn;
+
+///
+/// 发出的 QoS 1/2 消息所处的确认阶段
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InflightState {
+    /// QoS 1 等待 PUBACK
+    WaitPuback,
+    /// QoS 2 等待 PUBREC
+    WaitPubrec,
+    /// QoS 2 已发送 PUBREL，等待 PUBCOMP
+    WaitPubcomp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inflight {
+    pub state: InflightState,
+    pub message_id: u16,
+    pub message: ApplicationMessage,
+    pub sent_at: Instant,
+    /// 通过共享订阅收到的消息对应的共享订阅过滤器
+    pub shared: Option<String>,
+}
+
+impl Inflight {
+    ///
+    /// 重发时使用的报文，PUBLISH 会设置 DUP 标志
+    ///
+    fn resend_bytes(&self, protocol_level: MqttProtocolLevel) -> Vec<u8> {
+        match self.state {
+            InflightState::WaitPubcomp => PubrelMessage::new(self.message_id).into_vec(),
+            _ => self.message.encode(protocol_level, self.message_id, MqttDup::Enable)
+        }
+    }
+}
+
+///
+/// 客户端会话：报文标识符分配、发出消息的确认状态、收到的 QoS 2 消息，
+/// 以及 clean_session 为 0 时需要在断线期间保留的订阅和待发送消息
+///
+#[derive(Debug, Default)]
+pub struct Session {
+    /// 当前连接的协议版本，发出的 PUBLISH 按此版本打包
+    protocol_level: Option<MqttProtocolLevel>,
+    /// 当前连接上发给客户端的主题别名
+    outbound_aliases: OutboundAliases,
+    last_packet_id: u16,
+    inflight: BTreeMap<u16, Inflight>,
+    /// 已回复 PUBREC、等待 PUBREL 的报文标识符
+    incoming: HashSet<u16>,
+    /// 订阅的主题过滤器及订阅选项
+    subscriptions: HashMap<String, SubscriptionOptions>,
+    /// 客户端离线期间收到的 QoS 1/2 消息
+    queue: VecDeque<ApplicationMessage>,
+    /// 断开连接后会话的过期时间，None 表示永不过期
+    expires_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    ///
+    /// 绑定到新的连接：客户端重连时可能使用不同的协议版本，主题别名也只在一个连接内有效
+    ///
+    pub fn attach(&mut self, protocol_level: MqttProtocolLevel, topic_alias_maximum: u16) {
+        self.protocol_level = Some(protocol_level);
+        self.outbound_aliases = OutboundAliases::new(topic_alias_maximum);
+    }
+
+    ///
+    /// 客户端断开时开始计算会话的过期时间，0xFFFFFFFF 表示永不过期
+    ///
+    pub fn set_expiry(&mut self, interval: u32) {
+        self.expires_at = match interval {
+            u32::MAX => None,
+            interval => Some(Instant::now() + Duration::from_secs(interval as u64)),
+        };
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() >= expires_at,
+            None => false
+        }
+    }
+
+    fn protocol_level(&self) -> MqttProtocolLevel {
+        self.protocol_level.unwrap_or(MqttProtocolLevel::Level3_1_1)
+    }
+
+    ///
+    /// 分配下一个未被占用的报文标识符，0 不可用，全部占用时返回 None
+    ///
+    pub fn next_packet_id(&mut self) -> Option<u16> {
+        for _ in 0..u16::MAX {
+            self.last_packet_id = self.last_packet_id.wrapping_add(1);
+            if self.last_packet_id == 0 {
+                self.last_packet_id = 1;
+            }
+            if !self.inflight.contains_key(&self.last_packet_id) {
+                return Some(self.last_packet_id);
+            }
+        }
+        None
+    }
+
+    pub fn inflight_len(&self) -> usize {
+        self.inflight.len()
+    }
+
+    pub fn is_inflight(&self, packet_id: u16) -> bool {
+        self.inflight.contains_key(&packet_id)
+    }
+
+    ///
+    /// 发送给客户端的消息，按连接的协议版本打包，
+    /// QoS 大于 0 时分配报文标识符并记录到 inflight 中
+    ///
+    pub fn publish(&mut self, msg: &ApplicationMessage) -> Option<Vec<u8>> {
+        self.deliver(msg, None)
+    }
+
+    ///
+    /// 发送通过共享订阅收到的消息，连接断开时未确认的 QoS 1 消息可以交给组内其它成员
+    ///
+    pub fn publish_shared(&mut self, msg: &ApplicationMessage, shared_filter: &str) -> Option<Vec<u8>> {
+        self.deliver(msg, Some(shared_filter.to_owned()))
+    }
+
+    fn deliver(&mut self, msg: &ApplicationMessage, shared: Option<String>) -> Option<Vec<u8>> {
+        if msg.qos == MqttQos::Qos0 {
+            return Some(self.encode(msg, 0));
+        }
+        let packet_id = self.next_packet_id()?;
+        let state = if msg.qos == MqttQos::Qos1 { InflightState::WaitPuback } else { InflightState::WaitPubrec };
+        self.inflight.insert(packet_id, Inflight { state, message_id: packet_id, message: msg.clone(), sent_at: Instant::now(), shared });
+        Some(self.encode(msg, packet_id))
+    }
+
+    ///
+    /// 取出通过共享订阅收到、还没有确认的 QoS 1 消息及其共享订阅过滤器
+    ///
+    pub fn take_shared(&mut self) -> Vec<(String, ApplicationMessage)> {
+        let packet_ids = self.inflight.values()
+            .filter(|inflight| inflight.state == InflightState::WaitPuback && inflight.shared.is_some())
+            .map(|inflight| inflight.message_id)
+            .collect::<Vec<u16>>();
+        packet_ids.into_iter()
+            .filter_map(|packet_id| self.inflight.remove(&packet_id))
+            .filter_map(|inflight| Some((inflight.shared?, inflight.message)))
+            .collect::<Vec<(String, ApplicationMessage)>>()
+    }
+
+    ///
+    /// 首次发送的 PUBLISH，v5 客户端允许时使用主题别名，重发时总是带上完整的主题名
+    ///
+    fn encode(&mut self, msg: &ApplicationMessage, message_id: u16) -> Vec<u8> {
+        let protocol_level = self.protocol_level();
+        if protocol_level == MqttProtocolLevel::Level5 {
+            if let Some((alias, known)) = self.outbound_aliases.alias(&msg.topic) {
+                return msg.to_v5_with_alias(message_id, MqttDup::Disable, alias, known).into_vec();
+            }
+        }
+        msg.encode(protocol_level, message_id, MqttDup::Disable)
+    }
+
+    ///
+    /// 收到 PUBACK，QoS 1 消息发送完成
+    ///
+    pub fn puback(&mut self, packet_id: u16) -> bool {
+        self.remove_in_state(packet_id, InflightState::WaitPuback)
+    }
+
+    ///
+    /// 收到 PUBREC，返回需要回复的 PUBREL
+    ///
+    pub fn pubrec(&mut self, packet_id: u16) -> Option<PubrelMessage> {
+        let inflight = self.inflight.get_mut(&packet_id)?;
+        match inflight.state {
+            InflightState::WaitPubrec | InflightState::WaitPubcomp => {
+                inflight.state = InflightState::WaitPubcomp;
+                inflight.sent_at = Instant::now();
+                Some(PubrelMessage::new(packet_id))
+            }
+            InflightState::WaitPuback => None
+        }
+    }
+
+    ///
+    /// 收到 PUBCOMP，QoS 2 消息发送完成
+    ///
+    pub fn pubcomp(&mut self, packet_id: u16) -> bool {
+        self.remove_in_state(packet_id, InflightState::WaitPubcomp)
+    }
+
+    ///
+    /// 客户端以错误原因码回复 PUBREC 时，该消息不再继续发送
+    ///
+    pub fn discard(&mut self, packet_id: u16) -> bool {
+        self.inflight.remove(&packet_id).is_some()
+    }
+
+    fn remove_in_state(&mut self, packet_id: u16, state: InflightState) -> bool {
+        match self.inflight.get(&packet_id) {
+            Some(inflight) if inflight.state == state => {
+                self.inflight.remove(&packet_id);
+                true
+            }
+            _ => false
+        }
+    }
+
+    ///
+    /// 收到 QoS 2 的 PUBLISH，返回 false 表示该报文标识符还在等待 PUBREL，消息不能重复转发
+    ///
+    pub fn receive_qos2(&mut self, packet_id: u16) -> bool {
+        self.incoming.insert(packet_id)
+    }
+
+    ///
+    /// 收到 PUBREL，释放报文标识符
+    ///
+    pub fn release_qos2(&mut self, packet_id: u16) -> bool {
+        self.incoming.remove(&packet_id)
+    }
+
+    pub fn subscribe<S: Into<String>>(&mut self, filter: S, options: SubscriptionOptions) {
+        self.subscriptions.insert(filter.into(), options);
+    }
+
+    pub fn unsubscribe<S: AsRef<str>>(&mut self, filter: S) {
+        self.subscriptions.remove(filter.as_ref());
+    }
+
+    pub fn subscriptions(&self) -> &HashMap<String, SubscriptionOptions> {
+        &self.subscriptions
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    ///
+    /// 客户端离线时缓存消息，先清理已经过期的消息，超过上限时丢弃最早的消息
+    ///
+    pub fn enqueue(&mut self, msg: ApplicationMessage, max_queued: usize) {
+        if max_queued == 0 {
+            return;
+        }
+        self.queue.retain(|queued| !queued.is_expired());
+        while self.queue.len() >= max_queued {
+            if let Some(dropped) = self.queue.pop_front() {
+                warn!("session queue is full, drop message on topic: {}", dropped.topic);
+            }
+        }
+        self.queue.push_back(msg);
+    }
+
+    ///
+    /// 客户端重连后需要发送的报文：先重发未确认的消息，再发送离线期间缓存且没有过期的消息
+    ///
+    pub fn resume(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = self.resend_all();
+        self.queue.retain(|msg| !msg.is_expired());
+        while let Some(msg) = self.queue.pop_front() {
+            match self.publish(&msg) {
+                Some(publish) => packets.push(publish),
+                None => {
+                    self.queue.push_front(msg);
+                    break;
+                }
+            }
+        }
+        packets
+    }
+
+    ///
+    /// 超时未确认的消息，PUBLISH 设置 DUP 后重发，PUBREL 原样重发；
+    /// v5 只允许在重连时重发，连接期间不做超时重发
+    ///
+    pub fn retry(&mut self, timeout: Duration) -> Vec<Vec<u8>> {
+        if self.protocol_level() == MqttProtocolLevel::Level5 {
+            return vec![];
+        }
+        self.resend(timeout)
+    }
+
+    ///
+    /// 重发所有未确认的消息，用于客户端重连
+    ///
+    pub fn resend_all(&mut self) -> Vec<Vec<u8>> {
+        self.resend(Duration::from_secs(0))
+    }
+
+    fn resend(&mut self, timeout: Duration) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let protocol_level = self.protocol_level();
+        self.inflight.values_mut()
+            .filter(|inflight| now.duration_since(inflight.sent_at) >= timeout)
+            .map(|inflight| {
+                inflight.sent_at = now;
+                inflight.resend_bytes(protocol_level)
+            })
+            .collect::<Vec<Vec<u8>>>()
+    }
+}
+
+///
+/// clean_session 为 0 的客户端断开后保存的会话
+///
+pub struct SessionContainer {
+    container: Arc<Mutex<HashMap<ClientID, Session>>>,
+}
+
+impl Default for SessionContainer {
+    fn default() -> Self {
+        SessionContainer::new()
+    }
+}
+
+impl SessionContainer {
+    pub fn new() -> SessionContainer {
+        SessionContainer { container: Arc::new(Mutex::new(HashMap::default())) }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.container.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.container.lock().await.is_empty()
+    }
+
+    pub async fn contain<S: AsRef<ClientID>>(&self, client_id: S) -> bool {
+        self.container.lock().await.contains_key(client_id.as_ref())
+    }
+
+    pub async fn store(&self, client_id: ClientID, session: Session) -> Option<Session> {
+        self.container.lock().await.insert(client_id, session)
+    }
+
+    pub async fn take<S: AsRef<ClientID>>(&self, client_id: S) -> Option<Session> {
+        self.container.lock().await.remove(client_id.as_ref())
+    }
+
+    ///
+    /// 删除已经过期的会话，会话在此期间被客户端恢复或重新保存时不会删除
+    ///
+    pub async fn expire<S: AsRef<ClientID>>(&self, client_id: S) -> Option<Session> {
+        let mut container = self.container.lock().await;
+        match container.get(client_id.as_ref()) {
+            Some(session) if session.is_expired() => container.remove(client_id.as_ref()),
+            _ => None
+        }
+    }
+
+    pub async fn subscriptions<S: AsRef<ClientID>>(&self, client_id: S) -> Option<HashMap<String, SubscriptionOptions>> {
+        self.container.lock().await.get(client_id.as_ref()).map(|session| session.subscriptions().clone())
+    }
+
+    ///
+    /// 订阅者离线时缓存发给它的消息，只缓存 QoS 1/2 消息，返回是否已缓存
+    ///
+    pub async fn enqueue<S: AsRef<ClientID>>(&self, client_id: S, msg: &TopicMessage, options: SubscriptionOptions, max_queued: usize) -> bool {
+        let TopicMessage::Content(_, content) = msg;
+        let publish = options.forward(content);
+        if publish.qos == MqttQos::Qos0 {
+            return false;
+        }
+        match self.container.lock().await.get_mut(client_id.as_ref()) {
+            Some(session) => {
+                session.enqueue(publish, max_queued);
+                true
+            }
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::tools::protocol::MqttRetain;
+    use crate::mqtt::message::BaseMessage;
+    use crate::mqtt::message::v3::PublishMessage;
+    use crate::mqtt::hex::{Property, PropertyValue, PropertyItem};
+    use std::convert::TryFrom;
+
+    fn publish(qos: MqttQos) -> ApplicationMessage {
+        ApplicationMessage::new("a/b", b"hello".to_vec(), qos, MqttRetain::Disable)
+    }
+
+    #[test]
+    fn test() {
+        let mut session = Session::new();
+        session.publish(&publish(MqttQos::Qos1)).unwrap();
+        session.publish(&publish(MqttQos::Qos2)).unwrap();
+        let qos0 = session.publish(&publish(MqttQos::Qos0)).unwrap();
+        assert!(session.is_inflight(1));
+        assert!(session.is_inflight(2));
+        assert_eq!(PublishMessage::try_from(BaseMessage::try_from(qos0).unwrap()).unwrap().message_id, 0);
+        assert_eq!(session.inflight_len(), 2);
+
+        assert!(!session.pubcomp(2));
+        assert!(session.pubrec(2).is_some());
+        assert!(session.puback(1));
+        assert!(!session.puback(1));
+
+        let resend = session.resend_all();
+        assert_eq!(resend, vec![PubrelMessage::new(2).into_vec()]);
+        assert!(session.pubcomp(2));
+        assert_eq!(session.inflight_len(), 0);
+    }
+
+    #[test]
+    fn test_protocol_level() {
+        let mut msg = publish(MqttQos::Qos1);
+        msg.properties.push(PropertyItem(Property::ContentType, PropertyValue::String("text/plain".to_string())));
+
+        let mut session = Session::new();
+        let v3 = session.publish(&msg).unwrap();
+        let v3 = PublishMessage::try_from(BaseMessage::try_from(v3).unwrap()).unwrap();
+        assert_eq!(v3.msg_body, b"hello".to_vec());
+
+        session.attach(MqttProtocolLevel::Level5, 0);
+        let v5 = session.publish(&msg).unwrap();
+        let v5 = crate::mqtt::message::v5::PublishMessage::try_from(BaseMessage::try_from(v5).unwrap()).unwrap();
+        assert_eq!(v5.msg_body, b"hello".to_vec());
+        assert_eq!(v5.properties.unwrap().len(), 1);
+        // v5 连接期间不超时重发，重连时才重发
+        assert!(session.retry(Duration::from_secs(0)).is_empty());
+        assert_eq!(session.resend_all().len(), 2);
+    }
+
+    #[test]
+    fn test_topic_alias() {
+        let decode = |bytes: Vec<u8>| crate::mqtt::message::v5::PublishMessage::try_from(BaseMessage::try_from(bytes).unwrap()).unwrap();
+        let mut session = Session::new();
+        session.attach(MqttProtocolLevel::Level5, 1);
+        let first = decode(session.publish(&publish(MqttQos::Qos0)).unwrap());
+        assert_eq!(first.topic, "a/b");
+        let second = decode(session.publish(&publish(MqttQos::Qos0)).unwrap());
+        assert_eq!(second.topic, "");
+        let alias = crate::mqtt::hex::find_property(second.properties.as_deref().unwrap(), Property::TopicAlias);
+        assert_eq!(alias.and_then(PropertyItem::as_short), Some(1));
+
+        // 重连后别名失效
+        session.attach(MqttProtocolLevel::Level5, 1);
+        assert_eq!(decode(session.publish(&publish(MqttQos::Qos0)).unwrap()).topic, "a/b");
+    }
+
+    #[test]
+    fn test_take_shared() {
+        let mut session = Session::new();
+        session.publish_shared(&publish(MqttQos::Qos1), "$share/workers/a/#").unwrap();
+        session.publish_shared(&publish(MqttQos::Qos1), "$share/workers/a/#").unwrap();
+        session.publish_shared(&publish(MqttQos::Qos2), "$share/workers/a/#").unwrap();
+        session.publish(&publish(MqttQos::Qos1)).unwrap();
+        assert!(session.puback(1));
+
+        let shared = session.take_shared();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].0, "$share/workers/a/#");
+        assert!(!session.is_inflight(2));
+        assert_eq!(session.inflight_len(), 2);
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let mut session = Session::new();
+        session.attach(MqttProtocolLevel::Level5, 0);
+        session.enqueue(publish(MqttQos::Qos1).with_expiry(Some(0)), 10);
+        session.enqueue(publish(MqttQos::Qos1).with_expiry(Some(60)), 10);
+        assert_eq!(session.queue_len(), 1);
+
+        let packets = session.resume();
+        assert_eq!(packets.len(), 1);
+        let v5 = crate::mqtt::message::v5::PublishMessage::try_from(BaseMessage::try_from(packets[0].clone()).unwrap()).unwrap();
+        let interval = crate::mqtt::hex::find_property(v5.properties.as_deref().unwrap(), Property::MessageExpiryInterval);
+        assert_eq!(interval.and_then(PropertyItem::as_long), Some(60));
+    }
+
+    #[tokio::test]
+    async fn test_session_expiry() {
+        let sessions = SessionContainer::new();
+        let mut expired = Session::new();
+        expired.set_expiry(0);
+        let mut kept = Session::new();
+        kept.set_expiry(60);
+        let mut forever = Session::new();
+        forever.set_expiry(u32::MAX);
+        sessions.store(ClientID::from("a"), expired).await;
+        sessions.store(ClientID::from("b"), kept).await;
+        sessions.store(ClientID::from("c"), forever).await;
+
+        assert!(sessions.expire(ClientID::from("a")).await.is_some());
+        assert!(sessions.expire(ClientID::from("b")).await.is_none());
+        assert!(sessions.expire(ClientID::from("c")).await.is_none());
+        assert_eq!(sessions.len().await, 2);
+    }
+
+    #[test]
+    fn test_resend_dup() {
+        let mut session = Session::new();
+        let msg = session.publish(&publish(MqttQos::Qos1)).unwrap();
+        let resend = session.resend_all();
+        assert_eq!(resend.len(), 1);
+        assert_eq!(resend[0][0], msg[0] | 0b1000);
+        assert_eq!(&resend[0][1..], &msg[1..]);
+    }
+
+    #[test]
+    fn test_incoming_qos2() {
+        let mut session = Session::new();
+        assert!(session.receive_qos2(7));
+        assert!(!session.receive_qos2(7));
+        assert!(session.release_qos2(7));
+        assert!(!session.release_qos2(7));
+        assert!(session.receive_qos2(7));
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut session = Session::new();
+        session.enqueue(publish(MqttQos::Qos1), 2);
+        session.enqueue(publish(MqttQos::Qos2), 2);
+        session.enqueue(publish(MqttQos::Qos1), 2);
+        assert_eq!(session.queue_len(), 2);
+
+        let inflight = session.publish(&publish(MqttQos::Qos1)).unwrap();
+        let packets = session.resume();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0][0], inflight[0] | 0b1000);
+        assert_eq!(session.queue_len(), 0);
+        assert_eq!(session.inflight_len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_container() {
+        let sessions = SessionContainer::new();
+        let client_id = ClientID::from("kiosk-1");
+        let msg = TopicMessage::Content(ClientID::from("server"), publish(MqttQos::Qos2));
+        assert!(!sessions.enqueue(&client_id, &msg, SubscriptionOptions::new(MqttQos::Qos1), 10).await);
+
+        sessions.store(client_id.clone(), Session::new()).await;
+        assert!(sessions.enqueue(&client_id, &msg, SubscriptionOptions::new(MqttQos::Qos1), 10).await);
+        assert!(!sessions.enqueue(&client_id, &msg, SubscriptionOptions::new(MqttQos::Qos0), 10).await);
+
+        let mut session = sessions.take(&client_id).await.unwrap();
+        let packets = session.resume();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], 0b0011_0010);
+        assert!(sessions.is_empty().await);
+    }
+
+    #[test]
+    fn test_packet_id() {
+        let mut session = Session::new();
+        session.last_packet_id = u16::MAX - 1;
+        assert_eq!(session.next_packet_id(), Some(u16::MAX));
+        assert_eq!(session.next_packet_id(), Some(1));
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use crate::mqtt::hex::reason_code::ReasonPhrases;
+
+///
+/// 报文解析错误
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// 报文长度不足
+    Truncated,
+    /// 变长整数格式错误
+    MalformedVarInt,
+    /// 字符串不是合法的 UTF-8
+    InvalidUtf8,
+    /// 未知的报文种类
+    UnknownType(u8),
+    /// 固定报头中的保留标志位被设置
+    ReservedFlags(u8),
+    /// 不支持的协议版本
+    UnsupportedProtocolLevel(u8),
+    /// 非法的 QoS 等级
+    InvalidQos(u8),
+    /// 未知的属性标识
+    UnknownProperty(u8),
+    /// 属性不能出现在当前报文中
+    InvalidProperty(u8),
+    /// 未知的原因码
+    InvalidReasonCode(u8),
+    /// 报文超过服务端允许的最大长度
+    PacketTooLarge,
+    /// 其它报文结构错误
+    Malformed(&'static str),
+}
+
+impl DecodeError {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DecodeError::Truncated => { "packet truncated" }
+            DecodeError::MalformedVarInt => { "malformed variable byte integer" }
+            DecodeError::InvalidUtf8 => { "invalid utf-8 string" }
+            DecodeError::UnknownType(_) => { "unknown packet type" }
+            DecodeError::ReservedFlags(_) => { "reserved flags set" }
+            DecodeError::UnsupportedProtocolLevel(_) => { "unsupported protocol level" }
+            DecodeError::InvalidQos(_) => { "invalid qos" }
+            DecodeError::UnknownProperty(_) => { "unknown property" }
+            DecodeError::InvalidProperty(_) => { "property not allowed in packet" }
+            DecodeError::InvalidReasonCode(_) => { "invalid reason code" }
+            DecodeError::PacketTooLarge => { "packet too large" }
+            DecodeError::Malformed(msg) => { msg }
+        }
+    }
+
+    ///
+    /// 关闭连接时回复给 v5 客户端的原因码
+    ///
+    pub fn as_reason_phrase(&self) -> ReasonPhrases {
+        match *self {
+            DecodeError::UnsupportedProtocolLevel(_) => { ReasonPhrases::UnsupportedProtocolVersion }
+            DecodeError::InvalidProperty(_) |
+            DecodeError::UnknownType(_) => { ReasonPhrases::ProtocolError }
+            DecodeError::PacketTooLarge => { ReasonPhrases::PacketTooLarge }
+            _ => { ReasonPhrases::MalformedPacket }
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DecodeError::UnknownType(value) |
+            DecodeError::ReservedFlags(value) |
+            DecodeError::UnsupportedProtocolLevel(value) |
+            DecodeError::InvalidQos(value) |
+            DecodeError::UnknownProperty(value) |
+            DecodeError::InvalidProperty(value) |
+            DecodeError::InvalidReasonCode(value) => write!(f, "{}: {:#04x}", self.as_str(), value),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,126 @@
+use crate::mqtt::tools::un_pack_tool::get_remaining_length;
+use crate::mqtt::tools::error::DecodeError;
+
+///
+/// 剩余长度字段最多占用 4 个字节
+///
+const MAX_REMAINING_LENGTH_BYTES: usize = 4;
+
+///
+/// 按连接缓存字节流，根据固定报头中的剩余长度切分出完整报文
+///
+/// 一次 read 可能包含多个报文，一个报文也可能分多次 read 到达
+///
+#[derive(Debug)]
+pub struct PacketFramer {
+    buffer: Vec<u8>,
+    /// 允许的最大报文长度，包括固定报头
+    max_packet_size: usize,
+}
+
+impl PacketFramer {
+    pub fn new(max_packet_size: usize) -> PacketFramer {
+        PacketFramer { buffer: vec![], max_packet_size }
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    ///
+    /// 取出下一个完整报文，数据不足时返回 `Ok(None)`，
+    /// 解析出固定报头后报文超过最大长度时立即返回错误，不再等待剩余数据
+    ///
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>, DecodeError> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let header_complete = self.buffer.iter()
+            .skip(1)
+            .take(MAX_REMAINING_LENGTH_BYTES)
+            .any(|byte| byte & 128 == 0);
+        if !header_complete {
+            return if self.buffer.len() > MAX_REMAINING_LENGTH_BYTES {
+                Err(DecodeError::MalformedVarInt)
+            } else {
+                Ok(None)
+            };
+        }
+
+        let (remaining_length, head_bytes) = get_remaining_length(self.buffer.as_slice())?;
+        let packet_length = head_bytes + remaining_length;
+        if packet_length > self.max_packet_size {
+            return Err(DecodeError::PacketTooLarge);
+        }
+        if self.buffer.len() < packet_length {
+            return Ok(None);
+        }
+
+        Ok(Some(self.buffer.drain(..packet_length).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let mut framer = PacketFramer::new(1024);
+        // PINGREQ + 不完整的 PUBLISH
+        framer.extend(&[192, 0, 48, 7, 0, 3, 97]);
+        assert_eq!(framer.next_packet(), Ok(Some(vec![192, 0])));
+        assert_eq!(framer.next_packet(), Ok(None));
+
+        framer.extend(&[47, 98, 104, 105, 192]);
+        assert_eq!(framer.next_packet(), Ok(Some(vec![48, 7, 0, 3, 97, 47, 98, 104, 105])));
+        assert_eq!(framer.next_packet(), Ok(None));
+
+        framer.extend(&[0]);
+        assert_eq!(framer.next_packet(), Ok(Some(vec![192, 0])));
+        assert!(framer.is_empty());
+    }
+
+    #[test]
+    fn test_large_packet() {
+        let mut packet = vec![48, 0xD0, 0x0F];
+        packet.extend(vec![0_u8; 2000]);
+
+        let mut framer = PacketFramer::new(4096);
+        for chunk in packet.chunks(1024) {
+            assert_eq!(framer.next_packet(), Ok(None));
+            framer.extend(chunk);
+        }
+        assert_eq!(framer.next_packet(), Ok(Some(packet)));
+    }
+
+    #[test]
+    fn test_malformed_length() {
+        let mut framer = PacketFramer::new(1024);
+        framer.extend(&[48, 255, 255]);
+        assert_eq!(framer.next_packet(), Ok(None));
+        framer.extend(&[255, 255]);
+        assert_eq!(framer.next_packet(), Err(DecodeError::MalformedVarInt));
+    }
+
+    #[test]
+    fn test_packet_too_large() {
+        let mut framer = PacketFramer::new(1024);
+        // 剩余长度 2000，只收到固定报头就拒绝
+        framer.extend(&[48, 0xD0, 0x0F]);
+        assert_eq!(framer.next_packet(), Err(DecodeError::PacketTooLarge));
+
+        let mut framer = PacketFramer::new(4);
+        framer.extend(&[48, 2, 0, 0]);
+        assert_eq!(framer.next_packet(), Ok(Some(vec![48, 2, 0, 0])));
+    }
+}
@@ -3,6 +3,10 @@ pub mod pack_tool;
 pub mod config;
 pub mod protocol;
 pub mod types;
+pub mod framer;
+pub mod error;
+pub mod topic;
+pub mod topic_alias;
 
 
 #[cfg(test)]
@@ -12,6 +12,24 @@ pub fn pack_string(str: &String) -> Vec<u8> {
     content
 }
 
+///
+/// 包装报文 binary 数组
+///
+pub fn pack_binary(data: &[u8]) -> Vec<u8> {
+    let mut content = pack_short_int(data.len() as u16);
+    content.extend_from_slice(data);
+    content
+}
+
+///
+/// 包装报文 string pair 数组
+///
+pub fn pack_string_pair(key: &String, value: &String) -> Vec<u8> {
+    let mut content = pack_string(key);
+    content.extend(pack_string(value));
+    content
+}
+
 ///
 /// 包装报文 byte 数组
 ///
@@ -92,7 +110,7 @@ pub fn pack_connect_flags(
     will_qos: MqttQos,
     will_retain: MqttRetain,
     username_flag: Option<&String>,
-    password_flag: Option<&String>,
+    password_flag: Option<&Vec<u8>>,
 ) -> Result<u8, String> {
     let mut connect_flags = 0_u8;
     if clean_session == MqttCleanSession::Enable {
@@ -131,7 +149,7 @@ pub fn pack_will_topic(msg: &ConnectMessage) -> Option<Vec<u8>> {
 
 pub fn pack_will_message(msg: &ConnectMessage) -> Option<Vec<u8>> {
     if msg.payload.will_message.is_some() {
-        return Some(pack_string(msg.payload.will_message.as_ref().unwrap()));
+        return Some(pack_binary(msg.payload.will_message.as_ref().unwrap()));
     }
     None
 }
@@ -145,7 +163,7 @@ pub fn pack_username(msg: &ConnectMessage) -> Option<Vec<u8>> {
 
 pub fn pack_password(msg: &ConnectMessage) -> Option<Vec<u8>> {
     if msg.payload.password.is_some() {
-        return Some(pack_string(msg.payload.password.as_ref().unwrap()));
+        return Some(pack_binary(msg.payload.password.as_ref().unwrap()));
     }
     None
 }
@@ -4,6 +4,11 @@ pub const MQISDP_PROTOCOL_NAME: &'static str = "MQIsdp";
 
 pub const MQTT_PROTOCOL_NAME: &'static str = "MQTT";
 
+///
+/// MQTT 3.1 的客户端标识最多 23 个字符
+///
+pub const MQISDP_MAX_CLIENT_ID_LENGTH: usize = 23;
+
 #[derive(Debug, Copy, Clone, TryFromPrimitive, Ord, PartialOrd, Eq, PartialEq)]
 #[repr(u8)]
 pub enum MqttProtocolLevel {
@@ -24,6 +29,16 @@ impl MqttProtocolLevel{
     pub fn is_level_5(&self) -> bool {
         matches!(self, MqttProtocolLevel::Level5)
     }
+
+    ///
+    /// 协议版本对应的协议名，3.1 为 MQIsdp，之后的版本为 MQTT
+    ///
+    pub fn protocol_name(&self) -> &'static str {
+        match self {
+            MqttProtocolLevel::Level3_1 => MQISDP_PROTOCOL_NAME,
+            _ => MQTT_PROTOCOL_NAME,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, TryFromPrimitive, Ord, PartialOrd, Eq, PartialEq)]
@@ -126,3 +141,17 @@ pub enum MqttRetainAsPublished {
     Disable = 0,
     Enable = 1,
 }
+
+///
+/// 订阅时是否发送匹配的保留消息
+///
+#[derive(Debug, Copy, Clone, TryFromPrimitive, Ord, PartialOrd, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MqttRetainHandling {
+    /// 订阅时发送
+    SendOnSubscribe = 0,
+    /// 只在订阅原先不存在时发送
+    SendIfNew = 1,
+    /// 不发送
+    DoNotSend = 2,
+}
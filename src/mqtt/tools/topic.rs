@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::fmt;
+
+///
+/// 主题层级分隔符
+///
+pub const TOPIC_LEVEL_SEPARATOR: char = '/';
+
+///
+/// 单层通配符
+///
+pub const SINGLE_LEVEL_WILDCARD: &str = "+";
+
+///
+/// 多层通配符，只能出现在过滤器的最后一层
+///
+pub const MULTI_LEVEL_WILDCARD: &str = "#";
+
+///
+/// 共享订阅过滤器的前缀，格式为 `$share/{ShareName}/{filter}`
+///
+pub const SHARED_SUBSCRIPTION_PREFIX: &str = "$share/";
+
+///
+/// 协议允许的主题最大字节数
+///
+pub const MAX_TOPIC_LENGTH: usize = 65535;
+
+///
+/// 主题校验失败的原因
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TopicError {
+    /// 主题为空
+    Empty,
+    /// 主题包含 U+0000
+    NullCharacter,
+    /// 主题名包含通配符
+    Wildcard,
+    /// 通配符没有独占一层或 `#` 不在最后一层
+    InvalidWildcard,
+    /// 共享订阅缺少共享名或共享名包含通配符
+    InvalidShareName,
+    /// 主题超出最大长度
+    TooLong(usize),
+    /// 主题层级超出最大深度
+    TooManyLevels(usize),
+}
+
+impl TopicError {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TopicError::Empty => { "topic is empty" }
+            TopicError::NullCharacter => { "topic contains null character" }
+            TopicError::Wildcard => { "topic name contains wildcard" }
+            TopicError::InvalidWildcard => { "topic filter contains invalid wildcard" }
+            TopicError::InvalidShareName => { "shared subscription has invalid share name" }
+            TopicError::TooLong(_) => { "topic is too long" }
+            TopicError::TooManyLevels(_) => { "topic has too many levels" }
+        }
+    }
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TopicError::TooLong(value) |
+            TopicError::TooManyLevels(value) => write!(f, "{}: {}", self.as_str(), value),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+impl std::error::Error for TopicError {}
+
+///
+/// 主题的长度与层级限制
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TopicLimits {
+    pub max_length: usize,
+    pub max_levels: usize,
+}
+
+impl Default for TopicLimits {
+    fn default() -> Self {
+        TopicLimits { max_length: MAX_TOPIC_LENGTH, max_levels: MAX_TOPIC_LENGTH }
+    }
+}
+
+impl TopicLimits {
+    pub fn new(max_length: usize, max_levels: usize) -> TopicLimits {
+        TopicLimits { max_length, max_levels }
+    }
+
+    fn check(&self, topic: &str) -> Result<(), TopicError> {
+        if topic.is_empty() {
+            return Err(TopicError::Empty);
+        }
+        if topic.contains('\u{0}') {
+            return Err(TopicError::NullCharacter);
+        }
+        if topic.len() > self.max_length {
+            return Err(TopicError::TooLong(topic.len()));
+        }
+        let levels = topic.split(TOPIC_LEVEL_SEPARATOR).count();
+        if levels > self.max_levels {
+            return Err(TopicError::TooManyLevels(levels));
+        }
+        Ok(())
+    }
+}
+
+///
+/// 拆分共享订阅过滤器，返回共享名及实际的主题过滤器，普通过滤器返回 None
+///
+pub fn split_shared(filter: &str) -> Option<(&str, &str)> {
+    filter.strip_prefix(SHARED_SUBSCRIPTION_PREFIX)?.split_once(TOPIC_LEVEL_SEPARATOR)
+}
+
+///
+/// 校验订阅用的主题过滤器，共享订阅校验共享名及其后的过滤器
+///
+pub fn validate_filter<S: AsRef<str>>(filter: S, limits: &TopicLimits) -> Result<(), TopicError> {
+    let filter = filter.as_ref();
+    limits.check(filter)?;
+    let filter = match split_shared(filter) {
+        Some((share_name, filter)) => {
+            if share_name.is_empty() || share_name.contains(['+', '#']) {
+                return Err(TopicError::InvalidShareName);
+            }
+            limits.check(filter)?;
+            filter
+        }
+        None if filter.starts_with(SHARED_SUBSCRIPTION_PREFIX) => return Err(TopicError::InvalidShareName),
+        None => filter,
+    };
+    let levels = filter.split(TOPIC_LEVEL_SEPARATOR).collect::<Vec<&str>>();
+    let last = levels.len() - 1;
+    let valid = levels.iter().enumerate().all(|(index, level)| {
+        if level.contains('#') {
+            *level == MULTI_LEVEL_WILDCARD && index == last
+        } else if level.contains('+') {
+            *level == SINGLE_LEVEL_WILDCARD
+        } else {
+            true
+        }
+    });
+    if valid { Ok(()) } else { Err(TopicError::InvalidWildcard) }
+}
+
+///
+/// 校验发布用的主题名，主题名不能包含通配符
+///
+pub fn validate_topic_name<S: AsRef<str>>(topic_name: S, limits: &TopicLimits) -> Result<(), TopicError> {
+    let topic_name = topic_name.as_ref();
+    limits.check(topic_name)?;
+    if topic_name.contains(['+', '#']) {
+        return Err(TopicError::Wildcard);
+    }
+    Ok(())
+}
+
+///
+/// 主题过滤器是否合法
+///
+pub fn is_valid_filter<S: AsRef<str>>(filter: S) -> bool {
+    validate_filter(filter, &TopicLimits::default()).is_ok()
+}
+
+///
+/// 发布用的主题名是否合法
+///
+pub fn is_valid_topic_name<S: AsRef<str>>(topic_name: S) -> bool {
+    validate_topic_name(topic_name, &TopicLimits::default()).is_ok()
+}
+
+///
+/// 主题过滤器是否匹配主题名，逐层比较，不需要构建订阅树
+///
+pub fn is_match<S: AsRef<str>, SS: AsRef<str>>(filter: S, topic_name: SS) -> bool {
+    let topic_name = topic_name.as_ref();
+    let is_system = topic_name.starts_with('$');
+    let mut filter_levels = filter.as_ref().split(TOPIC_LEVEL_SEPARATOR);
+    let mut topic_levels = topic_name.split(TOPIC_LEVEL_SEPARATOR);
+    let mut is_first = true;
+    loop {
+        // 以 $ 开头的主题不会被首层的通配符匹配
+        let wildcard_allowed = !(is_first && is_system);
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) => return wildcard_allowed,
+            (Some(SINGLE_LEVEL_WILDCARD), Some(_)) if wildcard_allowed => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+        is_first = false;
+    }
+}
+
+#[derive(Debug)]
+struct TopicNode<T> {
+    value: Option<T>,
+    children: HashMap<String, TopicNode<T>>,
+}
+
+impl<T> Default for TopicNode<T> {
+    fn default() -> Self {
+        TopicNode { value: None, children: HashMap::new() }
+    }
+}
+
+impl<T> TopicNode<T> {
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<T> {
+        match levels.split_first() {
+            None => self.value.take(),
+            Some((level, rest)) => {
+                let child = self.children.get_mut(*level)?;
+                let value = child.remove(rest);
+                if child.is_empty() {
+                    self.children.remove(*level);
+                }
+                value
+            }
+        }
+    }
+
+    fn values<'a>(&'a self, out: &mut Vec<&'a T>) {
+        if let Some(ref value) = self.value {
+            out.push(value);
+        }
+        for child in self.children.values() {
+            child.values(out);
+        }
+    }
+
+    fn values_mut<'a>(&'a mut self, out: &mut Vec<&'a mut T>) {
+        if let Some(ref mut value) = self.value {
+            out.push(value);
+        }
+        for child in self.children.values_mut() {
+            child.values_mut(out);
+        }
+    }
+
+    fn matches<'a>(&'a self, levels: &[&str], is_first: bool, is_system: bool, out: &mut Vec<&'a T>) {
+        // 以 $ 开头的主题不会被首层的通配符匹配
+        let wildcard_allowed = !(is_first && is_system);
+
+        if wildcard_allowed {
+            if let Some(value) = self.children.get(MULTI_LEVEL_WILDCARD).and_then(|child| child.value.as_ref()) {
+                out.push(value);
+            }
+        }
+
+        match levels.split_first() {
+            None => {
+                if let Some(ref value) = self.value {
+                    out.push(value);
+                }
+            }
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.matches(rest, false, is_system, out);
+                }
+                if wildcard_allowed {
+                    if let Some(child) = self.children.get(SINGLE_LEVEL_WILDCARD) {
+                        child.matches(rest, false, is_system, out);
+                    }
+                }
+            }
+        }
+    }
+}
+
+///
+/// 按主题层级组织的订阅树，支持 `+` 与 `#` 通配符匹配
+///
+#[derive(Debug)]
+pub struct TopicTree<T> {
+    root: TopicNode<T>,
+    len: usize,
+}
+
+impl<T> Default for TopicTree<T> {
+    fn default() -> Self {
+        TopicTree { root: TopicNode::default(), len: 0 }
+    }
+}
+
+impl<T> TopicTree<T> {
+    pub fn new() -> TopicTree<T> {
+        TopicTree::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains_key<S: AsRef<str>>(&self, filter: S) -> bool {
+        self.get(filter).is_some()
+    }
+
+    pub fn get<S: AsRef<str>>(&self, filter: S) -> Option<&T> {
+        let mut node = &self.root;
+        for level in filter.as_ref().split(TOPIC_LEVEL_SEPARATOR) {
+            node = node.children.get(level)?;
+        }
+        node.value.as_ref()
+    }
+
+    pub fn get_mut<S: AsRef<str>>(&mut self, filter: S) -> Option<&mut T> {
+        let mut node = &mut self.root;
+        for level in filter.as_ref().split(TOPIC_LEVEL_SEPARATOR) {
+            node = node.children.get_mut(level)?;
+        }
+        node.value.as_mut()
+    }
+
+    pub fn insert<S: AsRef<str>>(&mut self, filter: S, value: T) -> Option<T> {
+        let mut node = &mut self.root;
+        for level in filter.as_ref().split(TOPIC_LEVEL_SEPARATOR) {
+            node = node.children.entry(level.to_owned()).or_default();
+        }
+        let old = node.value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove<S: AsRef<str>>(&mut self, filter: S) -> Option<T> {
+        let levels = filter.as_ref().split(TOPIC_LEVEL_SEPARATOR).collect::<Vec<&str>>();
+        let value = self.root.remove(levels.as_slice());
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    pub fn values(&self) -> Vec<&T> {
+        let mut out = vec![];
+        self.root.values(&mut out);
+        out
+    }
+
+    pub fn values_mut(&mut self) -> Vec<&mut T> {
+        let mut out = vec![];
+        self.root.values_mut(&mut out);
+        out
+    }
+
+    ///
+    /// 返回所有匹配主题名的过滤器对应的值
+    ///
+    pub fn matches<S: AsRef<str>>(&self, topic_name: S) -> Vec<&T> {
+        let topic_name = topic_name.as_ref();
+        let levels = topic_name.split(TOPIC_LEVEL_SEPARATOR).collect::<Vec<&str>>();
+        let mut out = vec![];
+        self.root.matches(levels.as_slice(), true, topic_name.starts_with('$'), &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        assert!(is_match("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(is_match("sport/tennis/player1/#", "sport/tennis/player1/ranking"));
+        assert!(is_match("sport/#", "sport"));
+        assert!(is_match("#", "sport/tennis"));
+        assert!(is_match("sport/tennis/+", "sport/tennis/player1"));
+        assert!(!is_match("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(is_match("sport/+", "sport/"));
+        assert!(!is_match("sport/+", "sport"));
+        assert!(is_match("+/+", "/finance"));
+        assert!(is_match("/+", "/finance"));
+        assert!(!is_match("+", "/finance"));
+        assert!(!is_match("#", "$SYS/broker"));
+        assert!(!is_match("+/broker", "$SYS/broker"));
+        assert!(is_match("$SYS/#", "$SYS/broker"));
+        assert!(is_match("$SYS/+", "$SYS/broker"));
+        assert!(!is_match("sport/tennis/#", "sport"));
+        assert!(!is_match("sport", "sport/tennis"));
+    }
+
+    #[test]
+    fn test_valid_filter() {
+        assert!(is_valid_filter("sport/tennis/#"));
+        assert!(is_valid_filter("+/tennis/+"));
+        assert!(is_valid_filter("/"));
+        assert!(!is_valid_filter(""));
+        assert!(!is_valid_filter("sport/tennis#"));
+        assert!(!is_valid_filter("sport/#/ranking"));
+        assert!(!is_valid_filter("sport+"));
+        assert!(is_valid_topic_name("sport/tennis"));
+        assert!(!is_valid_topic_name("sport/+"));
+        assert!(!is_valid_topic_name(""));
+        assert!(!is_valid_topic_name("sport\u{0}tennis"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = TopicLimits::new(10, 3);
+        assert_eq!(validate_topic_name("a/b/c", &limits), Ok(()));
+        assert_eq!(validate_topic_name("a/b/c/d", &limits), Err(TopicError::TooManyLevels(4)));
+        assert_eq!(validate_topic_name("abcdefghijk", &limits), Err(TopicError::TooLong(11)));
+        assert_eq!(validate_topic_name("a/#", &limits), Err(TopicError::Wildcard));
+        assert_eq!(validate_filter("a/#/b", &limits), Err(TopicError::InvalidWildcard));
+        assert_eq!(validate_filter("", &limits), Err(TopicError::Empty));
+        assert_eq!(validate_filter("a/+/#", &limits), Ok(()));
+    }
+
+    #[test]
+    fn test_shared() {
+        assert_eq!(split_shared("$share/workers/results/#"), Some(("workers", "results/#")));
+        assert_eq!(split_shared("results/#"), None);
+        assert!(is_valid_filter("$share/workers/results/#"));
+        assert!(is_valid_filter("$share/workers//"));
+        assert!(!is_valid_filter("$share/workers"));
+        assert!(!is_valid_filter("$share//results"));
+        assert!(!is_valid_filter("$share/work+/results"));
+        assert!(!is_valid_filter("$share/workers/results#"));
+        assert_eq!(validate_filter("$share/workers/", &TopicLimits::default()), Err(TopicError::Empty));
+    }
+
+    #[test]
+    fn test_tree() {
+        let mut tree = TopicTree::new();
+        tree.insert("a/b", 1);
+        tree.insert("a/+", 2);
+        tree.insert("a/#", 3);
+        tree.insert("b", 4);
+        assert_eq!(tree.len(), 4);
+
+        let mut values = tree.matches("a/b").into_iter().cloned().collect::<Vec<i32>>();
+        values.sort_unstable();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        assert_eq!(tree.remove("a/+"), Some(2));
+        assert_eq!(tree.remove("a/+"), None);
+        assert_eq!(tree.len(), 3);
+        assert!(!tree.contains_key("a/+"));
+        assert!(tree.contains_key("a/b"));
+        assert_eq!(tree.matches("a/c").into_iter().cloned().collect::<Vec<i32>>(), vec![3]);
+    }
+}
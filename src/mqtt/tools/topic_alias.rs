@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use crate::mqtt::hex::reason_code::ReasonPhrases;
+
+///
+/// 客户端发来的主题别名，只在当前连接内有效
+///
+#[derive(Debug, Default)]
+pub struct InboundAliases {
+    maximum: u16,
+    aliases: HashMap<u16, String>,
+}
+
+impl InboundAliases {
+    ///
+    /// maximum 为 CONNACK 中告知客户端的 TopicAliasMaximum
+    ///
+    pub fn new(maximum: u16) -> InboundAliases {
+        InboundAliases { maximum, aliases: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.aliases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    ///
+    /// 解析 PUBLISH 的主题：主题名不为空时更新别名，为空时使用别名对应的主题名
+    ///
+    pub fn resolve(&mut self, topic: &str, alias: Option<u16>) -> Result<String, ReasonPhrases> {
+        let alias = match alias {
+            Some(alias) => alias,
+            None => return Ok(topic.to_owned()),
+        };
+        if alias == 0 || alias > self.maximum {
+            return Err(ReasonPhrases::TopicAliasInvalid);
+        }
+        if topic.is_empty() {
+            return self.aliases.get(&alias).cloned().ok_or(ReasonPhrases::ProtocolError);
+        }
+        self.aliases.insert(alias, topic.to_owned());
+        Ok(topic.to_owned())
+    }
+}
+
+///
+/// 发给客户端的主题别名，数量不超过客户端 CONNECT 中的 TopicAliasMaximum
+///
+#[derive(Debug, Default)]
+pub struct OutboundAliases {
+    maximum: u16,
+    aliases: HashMap<String, u16>,
+}
+
+impl OutboundAliases {
+    pub fn new(maximum: u16) -> OutboundAliases {
+        OutboundAliases { maximum, aliases: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.aliases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    ///
+    /// 返回主题使用的别名以及客户端是否已经知道该别名，别名用完后不再分配
+    ///
+    pub fn alias<S: AsRef<str>>(&mut self, topic: S) -> Option<(u16, bool)> {
+        if let Some(alias) = self.aliases.get(topic.as_ref()) {
+            return Some((*alias, true));
+        }
+        if self.aliases.len() >= self.maximum as usize {
+            return None;
+        }
+        let alias = self.aliases.len() as u16 + 1;
+        self.aliases.insert(topic.as_ref().to_owned(), alias);
+        Some((alias, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let mut inbound = InboundAliases::new(2);
+        assert_eq!(inbound.resolve("a/b", None).ok(), Some("a/b".to_string()));
+        assert_eq!(inbound.resolve("a/b", Some(1)).ok(), Some("a/b".to_string()));
+        assert_eq!(inbound.resolve("", Some(1)).ok(), Some("a/b".to_string()));
+        assert!(matches!(inbound.resolve("", Some(2)), Err(ReasonPhrases::ProtocolError)));
+        assert!(matches!(inbound.resolve("c", Some(3)), Err(ReasonPhrases::TopicAliasInvalid)));
+        assert!(matches!(inbound.resolve("c", Some(0)), Err(ReasonPhrases::TopicAliasInvalid)));
+        assert_eq!(inbound.len(), 1);
+
+        let mut outbound = OutboundAliases::new(1);
+        assert_eq!(outbound.alias("a/b"), Some((1, false)));
+        assert_eq!(outbound.alias("a/b"), Some((1, true)));
+        assert_eq!(outbound.alias("c/d"), None);
+        assert!(OutboundAliases::default().alias("a/b").is_none());
+    }
+}
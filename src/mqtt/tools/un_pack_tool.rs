@@ -4,215 +4,247 @@ use crate::mqtt::tools::protocol::{MqttProtocolLevel, MqttCleanSession, MqttWill
 use crate::mqtt::message::v3::VariableHeader;
 use crate::mqtt::message::ConnectMessagePayload;
 use crate::mqtt::hex::un_pack_property;
+use crate::mqtt::tools::error::DecodeError;
 use log::{debug};
 
+///
+/// 固定报头：报文种类、发布标志位、剩余数据
+///
+pub type FixedHeader<'a> = (TypeKind, Option<MqttRetain>, Option<MqttQos>, Option<MqttDup>, &'a [u8]);
+
 ///
 /// 获取报文种类
 ///
-pub fn get_type(data: &[u8]) -> (Option<TypeKind>, Option<MqttRetain>, Option<MqttQos>, Option<MqttDup>, &[u8]) {
-    let kind = TypeKind::try_from(data[0] >> 4).ok();
-    if kind.unwrap() == TypeKind::PUBLISH {
-        let (retain, qos, dup) = get_publish_header(data[0]);
-        return (kind, retain, qos, dup, get_remaining_data(data));
+pub fn get_type(data: &[u8]) -> Result<FixedHeader<'_>, DecodeError> {
+    let (header, _) = parse_byte(data)?;
+    let kind = TypeKind::try_from(header >> 4).map_err(|_| DecodeError::UnknownType(header >> 4))?;
+    let flags = header & 0x0F;
+    match kind {
+        TypeKind::PUBLISH => {
+            let (retain, qos, dup) = get_publish_header(header)?;
+            return Ok((kind, Some(retain), Some(qos), Some(dup), get_remaining_data(data)?));
+        }
+        TypeKind::PUBREL | TypeKind::SUBSCRIBE | TypeKind::UNSUBSCRIBE => {
+            if flags != 0b0010 {
+                return Err(DecodeError::ReservedFlags(flags));
+            }
+        }
+        _ => {
+            if flags != 0 {
+                return Err(DecodeError::ReservedFlags(flags));
+            }
+        }
     }
-    (kind, None, None, None, get_remaining_data(data))
+    Ok((kind, None, None, None, get_remaining_data(data)?))
 }
 
 ///
 /// 获取协议名称和协议版本
 ///
-pub fn get_protocol_name_and_version(data: &[u8]) -> (Option<String>, Option<MqttProtocolLevel>) {
-    let slice = get_remaining_data(data);
-    let protocol_name = Option::from(String::from_utf8_lossy(slice).into_owned());
-    let mqtt_version = MqttProtocolLevel::try_from(data[6]).ok();
-    (protocol_name, mqtt_version)
+pub fn get_protocol_name_and_version(data: &[u8]) -> Result<(String, MqttProtocolLevel), DecodeError> {
+    let (protocol_name, last_data) = parse_string(data)?;
+    let (level, _) = parse_byte(last_data)?;
+    let mqtt_version = MqttProtocolLevel::try_from(level).map_err(|_| DecodeError::UnsupportedProtocolLevel(level))?;
+    Ok((protocol_name, mqtt_version))
 }
 
 ///
 /// 获取发布消息头
 ///
-pub fn get_publish_header(data: u8) -> (Option<MqttRetain>, Option<MqttQos>, Option<MqttDup>) {
+pub fn get_publish_header(data: u8) -> Result<(MqttRetain, MqttQos, MqttDup), DecodeError> {
     let retain = data & 1;
     let qos = (data >> 1) & 3;
     let dup = (data >> 3) & 1;
-    (
-        MqttRetain::try_from(retain).ok(),
-        MqttQos::try_from(qos).ok(),
-        MqttDup::try_from(dup).ok()
-    )
+    Ok((
+        MqttRetain::try_from(retain).map_err(|_| DecodeError::ReservedFlags(data & 0x0F))?,
+        MqttQos::try_from(qos).map_err(|_| DecodeError::InvalidQos(qos))?,
+        MqttDup::try_from(dup).map_err(|_| DecodeError::ReservedFlags(data & 0x0F))?,
+    ))
 }
 
 ///
 /// 获取 初始连接的 负载数据
 ///
-pub fn get_connect_payload_data(protocol_level: MqttProtocolLevel, data: &[u8], will_flag: MqttWillFlag, username_flag: MqttUsernameFlag, password_flag: MqttPasswordFlag) -> ConnectMessagePayload {
-    let (client_id, last_data) = parse_string(data).unwrap();
+pub fn get_connect_payload_data(protocol_level: MqttProtocolLevel, data: &[u8], will_flag: MqttWillFlag, username_flag: MqttUsernameFlag, password_flag: MqttPasswordFlag) -> Result<ConnectMessagePayload, DecodeError> {
+    let (client_id, last_data) = parse_string(data)?;
 
     let (properties, will_topic, will_message, last_data) = if MqttWillFlag::Enable == will_flag {
         let (properties, last_data) = if protocol_level == MqttProtocolLevel::Level5 {
-            let (properties_total_length, last_data) = parse_byte(last_data.unwrap());
-
-            if properties_total_length > 0 {
-                (Some(un_pack_property::will_properties(properties_total_length as u32, last_data)), last_data.get(properties_total_length as usize..))
-            } else {
-                (None, Some(last_data))
-            }
+            let (properties_total_length, last_data) = parse_var_int(last_data)?;
+            let properties = un_pack_property::will_properties(properties_total_length as u32, last_data)?;
+            (Some(properties), last_data.get(properties_total_length..).ok_or(DecodeError::Truncated)?)
         } else {
             (None, last_data)
         };
 
-        let (will_topic, will_last_data) = parse_string(last_data.unwrap()).unwrap();
-        let (will_message, will_last_data) = parse_string(will_last_data.unwrap()).unwrap();
+        let (will_topic, will_last_data) = parse_string(last_data)?;
+        let (will_message, will_last_data) = parse_binary(will_last_data)?;
         (properties, Some(will_topic), Some(will_message), will_last_data)
     } else {
         (None, None, None, last_data)
     };
 
     let (user_name, last_data) = if MqttUsernameFlag::Enable == username_flag {
-        parse_string(last_data.unwrap()).unwrap()
+        let (user_name, last_data) = parse_string(last_data)?;
+        (Some(user_name), last_data)
     } else {
-        ("".to_string(), last_data)
+        (None, last_data)
     };
 
-    let (password, _) = if MqttPasswordFlag::Enable == password_flag {
-        parse_string(last_data.unwrap()).unwrap()
+    let password = if MqttPasswordFlag::Enable == password_flag {
+        Some(parse_binary(last_data)?.0)
     } else {
-        ("".to_string(), last_data)
+        None
     };
     debug!("client ID: {}", client_id);
-    ConnectMessagePayload {
+    Ok(ConnectMessagePayload {
         client_id,
         will_topic,
         will_message,
-        user_name: Some(user_name),
-        password: Some(password),
+        user_name,
+        password,
         properties,
-    }
+    })
 }
 
 ///
 /// 获取可变报文头数据
 ///
-pub fn get_connect_variable_header(data: &[u8]) -> (VariableHeader, &[u8]) {
-    let slice = get_remaining_data(data);
-    let protocol_name = Option::from(String::from_utf8_lossy(slice).into_owned());
-    let clean_session = (data[7] >> 1) & 1;
-    let will_flag = (data[7] >> 2) & 1;
-    let will_qos = (data[7] >> 3) & 3;
-    let will_retain = (data[7] >> 5) & 1;
-    let password_flag = (data[7] >> 6) & 1;
-    let username_flag = (data[7] >> 7) & 1;
-    let (keep_alive, _) = parse_short_int(data.get(8..10).unwrap());
-
-    (
+pub fn get_connect_variable_header(data: &[u8]) -> Result<(VariableHeader, &[u8]), DecodeError> {
+    let (protocol_name, last_data) = parse_string(data)?;
+    let (protocol_level, last_data) = parse_byte(last_data)?;
+    let (connect_flags, last_data) = parse_byte(last_data)?;
+    let (keep_alive, last_data) = parse_short_int(last_data)?;
+    let protocol_level = MqttProtocolLevel::try_from(protocol_level).map_err(|_| DecodeError::UnsupportedProtocolLevel(protocol_level))?;
+    // 保留位必须为 0
+    if connect_flags & 1 != 0 {
+        return Err(DecodeError::ReservedFlags(connect_flags));
+    }
+    let clean_session = (connect_flags >> 1) & 1;
+    let will_flag = (connect_flags >> 2) & 1;
+    let will_qos = (connect_flags >> 3) & 3;
+    let will_retain = (connect_flags >> 5) & 1;
+    let password_flag = (connect_flags >> 6) & 1;
+    let username_flag = (connect_flags >> 7) & 1;
+    // 没有遗嘱时遗嘱 QoS 与遗嘱保留标志必须为 0
+    if will_flag == 0 && (will_qos != 0 || will_retain != 0) {
+        return Err(DecodeError::Malformed("will qos or will retain set without will flag"));
+    }
+
+    Ok((
         VariableHeader {
-            protocol_name,
+            protocol_name: Some(protocol_name),
             keep_alive: Some(keep_alive),
-            protocol_level: MqttProtocolLevel::try_from(data[6]).ok(),
+            protocol_level: Some(protocol_level),
             clean_session: MqttCleanSession::try_from(clean_session).ok(),
             will_flag: MqttWillFlag::try_from(will_flag).ok(),
-            will_qos: MqttQos::try_from(will_qos).ok(),
+            will_qos: Some(MqttQos::try_from(will_qos).map_err(|_| DecodeError::InvalidQos(will_qos))?),
             will_retain: MqttRetain::try_from(will_retain).ok(),
             password_flag: MqttPasswordFlag::try_from(password_flag).ok(),
             username_flag: MqttUsernameFlag::try_from(username_flag).ok(),
         },
-        data.get(10..).unwrap()
-    )
+        last_data
+    ))
 }
 
 ///
 /// 解析报文 byte 数据
 ///
-pub fn parse_byte(data: &[u8]) -> (u8, &[u8]) {
-    (data[0], data.get(1..).unwrap())
+pub fn parse_byte(data: &[u8]) -> Result<(u8, &[u8]), DecodeError> {
+    match data.split_first() {
+        Some((byte, last_data)) => Ok((*byte, last_data)),
+        None => Err(DecodeError::Truncated)
+    }
 }
 
 ///
 /// 解析报文 short int 数据
 ///
-pub fn parse_short_int(data: &[u8]) -> (u16, &[u8]) {
-    // println!("parse_short_int: {:?}", data.get(..2).unwrap());
-    let bytes = data.get(..2).unwrap();
-    let short_int_bytes = bytes.iter().rev().cloned().collect::<Vec<u8>>();
-    let short_int = u16::from_le_bytes(short_int_bytes.try_into().unwrap());
-    // println!("short int: {}", short_int);
-    (short_int, data.get(2..).unwrap())
+pub fn parse_short_int(data: &[u8]) -> Result<(u16, &[u8]), DecodeError> {
+    let bytes = data.get(..2).ok_or(DecodeError::Truncated)?;
+    let short_int = u16::from_be_bytes(bytes.try_into().map_err(|_| DecodeError::Truncated)?);
+    Ok((short_int, &data[2..]))
 }
 
 ///
 /// 解析报文 long int 数据
 ///
-pub fn parse_long_int(data: &[u8]) -> (u32, &[u8]) {
-    // println!("parse_long_int: {:?}", data.get(..4).unwrap());
-    let bytes = data.get(..4).unwrap();
-    let long_int_bytes = bytes.iter().rev().cloned().collect::<Vec<u8>>();
-    let long_int = u32::from_le_bytes(long_int_bytes.try_into().unwrap());
-    // println!("long int: {}", long_int);
-    (long_int, data.get(4..).unwrap())
+pub fn parse_long_int(data: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    let bytes = data.get(..4).ok_or(DecodeError::Truncated)?;
+    let long_int = u32::from_be_bytes(bytes.try_into().map_err(|_| DecodeError::Truncated)?);
+    Ok((long_int, &data[4..]))
+}
+
+///
+/// 解析报文 binary 数据，两个字节的长度后跟数据内容
+///
+pub fn parse_binary(data: &[u8]) -> Result<(Vec<u8>, &[u8]), DecodeError> {
+    let (length, last_data) = parse_short_int(data)?;
+    let value = last_data.get(..length as usize).ok_or(DecodeError::Truncated)?;
+    Ok((value.to_vec(), &last_data[length as usize..]))
 }
 
 ///
 /// 解析报文 string 数据
 ///
-pub fn parse_string(data: &[u8]) -> Result<(String, Option<&[u8]>), &str> {
-    let length = data[1];
-    if length as usize > data.len() {
-        return Err("parse string length error");
-    }
-    let value = get_remaining_data(data);
-    Ok((String::from_utf8(value.to_vec()).expect("parse utf-8 string"), data.get(2 + (length as usize)..)))
+pub fn parse_string(data: &[u8]) -> Result<(String, &[u8]), DecodeError> {
+    let (value, last_data) = parse_binary(data)?;
+    let value = String::from_utf8(value).map_err(|_| DecodeError::InvalidUtf8)?;
+    Ok((value, last_data))
 }
 
 ///
-/// 获取报文剩余长度数据
+/// 解析报文 string pair 数据
 ///
-/// from http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.pdf 第19页
+pub fn parse_string_pair(data: &[u8]) -> Result<((String, String), &[u8]), DecodeError> {
+    let (key, last_data) = parse_string(data)?;
+    let (value, last_data) = parse_string(last_data)?;
+    Ok(((key, value), last_data))
+}
+
 ///
+/// 解析变长整数 (Variable Byte Integer)
 ///
-pub fn get_remaining_length(data: &[u8]) -> Result<(usize, usize), &'static str> {
-    let (mut head_index, mut digit, mut multiplier, mut value) = (1_usize, 0, 1, 0);
+pub fn parse_var_int(data: &[u8]) -> Result<(usize, &[u8]), DecodeError> {
+    let (mut multiplier, mut value) = (1_usize, 0_usize);
 
-    loop {
-        digit = data[head_index] & 127;
-        value += digit as usize * multiplier;
-        multiplier *= 128;
-        if multiplier > 128 * 128 * 128 {
-            return Err("Malformed Variable Byte Integer");
+    for (index, encoded_byte) in data.iter().enumerate() {
+        if index == 4 {
+            return Err(DecodeError::MalformedVarInt);
+        }
+        value += (encoded_byte & 127) as usize * multiplier;
+        if (encoded_byte & 128) == 0 {
+            return Ok((value, &data[index + 1..]));
         }
-        head_index += 1;
-        if (digit & 128) == 0 { break; }
+        multiplier *= 128;
     }
 
-    Ok((value, head_index))
+    Err(DecodeError::Truncated)
 }
 
 ///
-/// 后续需要处理的数据
+/// 获取报文剩余长度数据
 ///
-pub fn get_remaining_data(data: &[u8]) -> &[u8] {
-    // println!("remaining_data handle data: {:?}", data);
-    let (remaining_length, head_bytes) = get_remaining_length(data).unwrap();
-    // println!("remaining_length: {}", remaining_length);
-    // println!("last_data: {:?}", data.get(head_bytes..(remaining_length + head_bytes)).unwrap());
-    data.get(head_bytes..(remaining_length + head_bytes)).unwrap()
+/// from http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.pdf 第19页
+///
+///
+pub fn get_remaining_length(data: &[u8]) -> Result<(usize, usize), DecodeError> {
+    let (value, last_data) = parse_var_int(data.get(1..).ok_or(DecodeError::Truncated)?)?;
+    Ok((value, data.len() - last_data.len()))
 }
 
-
-pub fn unpack_var_int(data: &[u8]) -> (String, &[u8]) {
-    let (remaining_length, head_bytes) = get_remaining_length(data).unwrap();
-    let (mut result, mut shift) = (0, 0);
-
-    for i in 0..head_bytes {
-        shift += 1;
-        result |= (data[i] & 127) << (shift * 7);
-    }
-    let val = String::from_utf8_lossy(data.get(head_bytes..(remaining_length + head_bytes)).unwrap()).into_owned();
-    (val, data.get((remaining_length + head_bytes)..).unwrap())
+///
+/// 后续需要处理的数据
+///
+pub fn get_remaining_data(data: &[u8]) -> Result<&[u8], DecodeError> {
+    let (remaining_length, head_bytes) = get_remaining_length(data)?;
+    data.get(head_bytes..(remaining_length + head_bytes)).ok_or(DecodeError::Truncated)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mqtt::tools::pack_tool::pack_string;
 
     #[test]
     fn test() {
@@ -226,6 +258,50 @@ mod tests {
         // let a =  3600_u32.to_ne_bytes();
     }
 
+    #[test]
+    fn test_var_int() {
+        assert_eq!(parse_var_int(&[0x00]), Ok((0, &[][..])));
+        assert_eq!(parse_var_int(&[0xC1, 0x02, 0x07]), Ok((321, &[0x07][..])));
+        assert_eq!(parse_var_int(&[0xFF, 0xFF, 0xFF, 0x7F]), Ok((268_435_455, &[][..])));
+        assert_eq!(parse_var_int(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01]), Err(DecodeError::MalformedVarInt));
+        assert_eq!(parse_var_int(&[0x80]), Err(DecodeError::Truncated));
+        assert_eq!(get_remaining_length(&[0x30, 0xC1, 0x02]), Ok((321, 3)));
+    }
+
+    #[test]
+    fn test_string() {
+        let long_topic = "a".repeat(300);
+        let mut data = pack_string(&long_topic);
+        data.push(7);
+        assert_eq!(parse_string(data.as_slice()), Ok((long_topic, &[7][..])));
+        assert_eq!(parse_string(&[0x00, 0x02, 0xC3, 0x28]), Err(DecodeError::InvalidUtf8));
+        assert_eq!(parse_binary(&[0x00, 0x02, 0xC3, 0x28]), Ok((vec![0xC3, 0x28], &[][..])));
+
+        let mut data = pack_string(&"key".to_string());
+        data.extend(pack_string(&"value".to_string()));
+        assert_eq!(parse_string_pair(data.as_slice()), Ok((("key".to_string(), "value".to_string()), &[][..])));
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert_eq!(parse_short_int(&[0x01]), Err(DecodeError::Truncated));
+        assert_eq!(parse_string(&[0x00, 0x05, 0x61]), Err(DecodeError::Truncated));
+        assert_eq!(get_type(&[0x30, 0x05, 0x00]).err(), Some(DecodeError::Truncated));
+        assert_eq!(get_type(&[0x00, 0x00]).err(), Some(DecodeError::UnknownType(0)));
+        assert_eq!(get_type(&[0x80, 0x00]).err(), Some(DecodeError::ReservedFlags(0)));
+        assert_eq!(get_type(&[0x36, 0x00]).err(), Some(DecodeError::InvalidQos(3)));
+    }
+
+    #[test]
+    fn test_connect_flags() {
+        let header = |flags: u8| vec![0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, flags, 0x00, 0x3C];
+        assert!(get_connect_variable_header(&header(0b0000_0010)).is_ok());
+        assert_eq!(get_connect_variable_header(&header(0b0000_0011)).err(), Some(DecodeError::ReservedFlags(0b0000_0011)));
+        assert!(get_connect_variable_header(&header(0b0010_1100)).is_ok());
+        assert!(matches!(get_connect_variable_header(&header(0b0000_1000)), Err(DecodeError::Malformed(_))));
+        assert!(matches!(get_connect_variable_header(&header(0b0010_0000)), Err(DecodeError::Malformed(_))));
+    }
+
     fn read_be_u16(input: &mut &[u8]) -> u16 {
         let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
         *input = rest;
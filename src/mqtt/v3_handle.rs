@@ -1,131 +1,236 @@
-use crate::mqtt::v3_server::{Line, TopicMessage, ClientID};
-use crate::mqtt::message::{BaseMessage, MqttMessageKind, v3};
-use crate::mqtt::message::v3::{MqttMessageV3, ConnackMessage, PublishMessage, PubackMessage, SubscribeMessage, UnsubscribeMessage, UnsubackMessage, DisconnectMessage, SubackMessage, PubrelMessage};
-use crate::mqtt::tools::protocol::MqttQos;
-use crate::{SUBSCRIPT, MACHINE_CONTAINER, MachineID, Machine, MachineStatus};
+use crate::mqtt::v3_server::{Line, TopicMessage, ClientID, Subscriber, SubscriptionOptions, publish};
+use crate::mqtt::message::application::ApplicationMessage;
+use crate::mqtt::message::{BaseMessage, MqttMessageKind, MqttBytesMessage};
+use crate::mqtt::message::v3::{MqttMessageV3, ConnackMessage, PublishMessage, PubackMessage, UnsubackMessage, DisconnectMessage, SubackMessage, PubrecMessage, PubcompMessage, ConnectMessage};
+use crate::mqtt::tools::protocol::{MqttQos, MqttRetain, MqttProtocolLevel, MqttSessionPresent, MqttCleanSession, MQISDP_MAX_CLIENT_ID_LENGTH};
+use crate::mqtt::tools::topic;
+use crate::mqtt::hex::reason_code::ReasonCodeV3;
+use crate::mqtt::client::{check_credentials, Credentials};
+use crate::{CONFIG, SUBSCRIPT, RETAIN};
 use log::{debug, info};
-use crate::http::MachineMessage;
 
 pub async fn match_v3_data(line: &mut Line, base_msg: BaseMessage) -> Option<MqttMessageKind> {
-    if let Some(v3) = MqttMessageKind::v3(base_msg) {
-        return match (
-            v3.is_v3(),
-            handle_v3(line, v3.get_v3()).await,
-            v3.is_v3s(),
-            v3.get_v3s()
-        ) {
-            (true, Some(res_msg), _, _) => {
-                if res_msg.is_disconnect() {
-                    Some(MqttMessageKind::Exit(res_msg.as_bytes().to_vec()))
-                } else {
-                    Some(MqttMessageKind::Response(res_msg.as_bytes().to_vec()))
+    let v3 = match MqttMessageKind::v3(base_msg) {
+        Ok(Some(v3)) => v3,
+        Ok(None) => return None,
+        Err(e) => return Some(line.handle_decode_error(e)),
+    };
+    // 发布或遗嘱使用非法主题名时直接关闭连接
+    let topic_name = match v3.get_v3() {
+        Some(MqttMessageV3::Publish(msg)) => Some(&msg.topic),
+        Some(MqttMessageV3::Connect(msg)) => msg.payload.will_topic.as_ref(),
+        _ => None
+    };
+    if let Some(topic_name) = topic_name {
+        if let Err(e) = topic::validate_topic_name(topic_name, &CONFIG.get_mqtt_topic_limits()) {
+            info!("invalid topic {:?}: {}", topic_name, e);
+            return Some(MqttMessageKind::Exit(vec![]));
+        }
+    }
+    match (
+        v3.is_v3(),
+        handle_v3(line, v3.get_v3()).await,
+        v3.is_v3s(),
+        v3.get_v3s()
+    ) {
+        (true, Some(res_msg), _, _) => {
+            if res_msg.is_disconnect() {
+                Some(MqttMessageKind::Exit(res_msg.as_bytes().to_vec()))
+            } else if let MqttMessageV3::Connack(ConnackMessage { return_code, .. }) = res_msg {
+                if return_code != ReasonCodeV3::ConnectionAccepted as u8 {
+                    return Some(MqttMessageKind::Exit(res_msg.as_bytes().to_vec()));
                 }
+                let mut res = res_msg.as_bytes().to_vec();
+                res.extend(line.resume_session());
+                Some(MqttMessageKind::Response(res))
+            } else {
+                Some(MqttMessageKind::Response(res_msg.as_bytes().to_vec()))
             }
-            (_, _, true, Some(items)) => {
-                let mut res = vec![];
-                for x in items {
-                    if let Some(res_msg) = handle_v3(line, Some(x)).await {
-                        res.push(res_msg.as_bytes().to_vec());
-                    }
-                }
-                Some(MqttMessageKind::Response(res.concat()))
+        }
+        (_, _, true, Some(items)) => {
+            match items.first() {
+                Some(MqttMessageV3::Subscribe(_)) => Some(handle_v3_subscribe(line, items).await),
+                Some(MqttMessageV3::Unsubscribe(_)) => Some(handle_v3_unsubscribe(line, items).await),
+                _ => None
             }
-            _ => None
-        };
+        }
+        _ => None
     }
-    None
 }
 
-async fn send_qrcdoe(id: String, qrcode_url: String) {
-    let machine_message = MachineMessage::qrcode(id.clone(),qrcode_url);
-    let topic = format!("{}-topic", id);
-    let publish_message = v3::PublishMessage::simple_new_msg(
-        topic,
-        0,
-        serde_json::to_string(&machine_message).unwrap(),
-    );
-    let topic_msg = TopicMessage::ContentV3(ClientID("idreamspace-server".to_string()), publish_message);
-    if let Some(topic) = topic_msg.get_topic() {
-        SUBSCRIPT.broadcast(topic, &topic_msg).await;
+///
+/// 发布 v3 客户端或服务端自身产生的消息
+///
+pub async fn publish_v3(from: ClientID, msg: PublishMessage) {
+    publish(from, ApplicationMessage::from(&msg)).await;
+}
+
+///
+/// 新订阅匹配到的保留消息，以 retain 为 1 发送，并附带该订阅的订阅标识符
+///
+pub async fn get_retain_messages(line: &mut Line, filter: &str, options: SubscriptionOptions) -> Vec<Vec<u8>> {
+    let identifiers = options.subscription_identifier.into_iter().collect::<Vec<u32>>();
+    // 共享订阅不发送保留消息
+    if topic::split_shared(filter).is_some() {
+        return vec![];
     }
+    RETAIN.matches(filter).await.into_iter()
+        .filter_map(|TopicMessage::Content(_, content)| {
+            line.publish_to_client(&content.forward(options.qos, MqttRetain::Enable).with_subscription_identifiers(&identifiers))
+        })
+        .collect::<Vec<Vec<u8>>>()
 }
 
 async fn handle_v3(line: &mut Line, kind_opt: Option<&MqttMessageV3>) -> Option<MqttMessageV3> {
     if let Some(kind) = kind_opt {
         match kind {
             MqttMessageV3::Connect(msg) => {
-                let machine_id = MachineID(msg.payload.client_id.clone());
-                if let Some(url) = MACHINE_CONTAINER.get_qrcode(&machine_id).await {
-                    send_qrcdoe(msg.payload.client_id.clone(), url).await;
+                if let Some(code) = check_v3_connect(msg) {
+                    info!("client {} connect rejected: {}", msg.payload.client_id, code.as_str());
+                    return Some(MqttMessageV3::Connack(ConnackMessage::new(MqttSessionPresent::Disable, code)));
                 }
-                MACHINE_CONTAINER.append(machine_id, Machine {
-                    id: msg.payload.client_id.clone(),
-                    qrcode_url: "".to_string(),
-                    status: MachineStatus::Online,
-                }).await;
                 line.init_v3(msg);
-                return Some(MqttMessageV3::Connack(ConnackMessage::default()));
+                line.take_over().await;
+                line.register_machine().await;
+                let session_present = line.init_session().await;
+                // MQTT 3.1 的 CONNACK 没有 session present 标志
+                let session_present = if msg.protocol_level == MqttProtocolLevel::Level3_1 { MqttSessionPresent::Disable } else { session_present };
+                return Some(MqttMessageV3::Connack(ConnackMessage::new(session_present, ReasonCodeV3::ConnectionAccepted)));
+            }
+            MqttMessageV3::Puback(msg) => {
+                if !line.session_mut().puback(msg.message_id) {
+                    debug!("unknown puback message id: {}", msg.message_id);
+                }
+                return None;
+            }
+            MqttMessageV3::Pubrec(msg) => return line.session_mut().pubrec(msg.message_id).map(MqttMessageV3::Pubrel),
+            MqttMessageV3::Pubrel(msg) => {
+                if !line.session_mut().release_qos2(msg.message_id) {
+                    debug!("unknown pubrel message id: {}", msg.message_id);
+                }
+                return Some(MqttMessageV3::Pubcomp(PubcompMessage::new(msg.message_id)));
+            }
+            MqttMessageV3::Pubcomp(msg) => {
+                if !line.session_mut().pubcomp(msg.message_id) {
+                    debug!("unknown pubcomp message id: {}", msg.message_id);
+                }
+                return None;
             }
-            // MqttMessageV3::Puback(msg) => {
-            // }
-            MqttMessageV3::Subscribe(msg) => return handle_v3_subscribe(line, msg).await,
-            MqttMessageV3::Unsubscribe(msg) => return handle_v3_unsubscribe(line, msg).await,
             MqttMessageV3::Publish(msg) => return handle_v3_publish(line, msg).await,
             MqttMessageV3::Pingresp(msg) => return Some(MqttMessageV3::Pingresp(msg.clone())),
             MqttMessageV3::Disconnect(_) => return handle_v3_disconnect(line).await,
-            MqttMessageV3::Pubrec(msg) => return Some(MqttMessageV3::Pubrel(PubrelMessage::new(msg.message_id))),
             _ => { return None; }
         }
     }
     None
 }
 
+///
+/// 检查 CONNECT 报文，返回拒绝连接时的返回码
+///
+fn check_v3_connect(msg: &ConnectMessage) -> Option<ReasonCodeV3> {
+    if msg.protocol_level == MqttProtocolLevel::Level3_1 {
+        let length = msg.payload.client_id.chars().count();
+        if length == 0 || length > MQISDP_MAX_CLIENT_ID_LENGTH {
+            return Some(ReasonCodeV3::IdentifierRejected);
+        }
+    }
+    // 空的客户端标识只能用于 clean session 为 1 的连接
+    if msg.payload.client_id.is_empty() && msg.clean_session == MqttCleanSession::Disable {
+        return Some(ReasonCodeV3::IdentifierRejected);
+    }
+    // 没有用户名时不能携带密码
+    if msg.payload.user_name.is_none() && msg.payload.password.is_some() {
+        return Some(ReasonCodeV3::BadUsernameOrPassword);
+    }
+    match check_credentials(msg.payload.user_name.as_deref(), msg.payload.password.as_deref()) {
+        Some(Credentials::Missing) => Some(ReasonCodeV3::NotAuthorized),
+        Some(Credentials::Invalid) => Some(ReasonCodeV3::BadUsernameOrPassword),
+        None => None
+    }
+}
+
+
 async fn handle_v3_publish(line: &mut Line, msg: &PublishMessage) -> Option<MqttMessageV3> {
-    let topic_msg = TopicMessage::ContentV3(line.get_client_id().to_owned(), msg.clone());
-    debug!("topic: {:?}", topic_msg);
-    SUBSCRIPT.broadcast(&msg.topic, &topic_msg).await;
-    if msg.qos == MqttQos::Qos1 {
-        return Some(MqttMessageV3::Puback(PubackMessage::new(msg.message_id)));
-    } else if msg.qos == MqttQos::Qos2 {}
-    return None;
+    match msg.qos {
+        MqttQos::Qos1 => {
+            publish_v3(line.get_client_id().to_owned(), msg.clone()).await;
+            Some(MqttMessageV3::Puback(PubackMessage::new(msg.message_id)))
+        }
+        MqttQos::Qos2 => {
+            // 报文标识符在收到 PUBREL 之前重复出现时只回复 PUBREC，不再转发
+            if line.session_mut().receive_qos2(msg.message_id) {
+                publish_v3(line.get_client_id().to_owned(), msg.clone()).await;
+            } else {
+                debug!("duplicate qos2 publish message id: {}", msg.message_id);
+            }
+            Some(MqttMessageV3::Pubrec(PubrecMessage::new(msg.message_id)))
+        }
+        _ => {
+            publish_v3(line.get_client_id().to_owned(), msg.clone()).await;
+            None
+        }
+    }
 }
 
-async fn handle_v3_subscribe(line: &mut Line, msg: &SubscribeMessage) -> Option<MqttMessageV3> {
-    debug!("{:?}", msg);
-    let topic = &msg.topic;
-    if SUBSCRIPT.contain(topic).await {
-        SUBSCRIPT.subscript(topic, line.get_client_id(), line.get_sender());
-    } else {
-        SUBSCRIPT.new_subscript(topic, line.get_client_id(), line.get_sender()).await;
+///
+/// 一个 SUBSCRIBE 报文中的所有主题过滤器，回复一个 SUBACK，之后发送匹配的保留消息
+///
+async fn handle_v3_subscribe(line: &mut Line, items: &[MqttMessageV3]) -> MqttMessageKind {
+    let limits = CONFIG.get_mqtt_topic_limits();
+    let mut message_id = 0;
+    let mut codes = vec![];
+    let mut retain_messages = vec![];
+    for item in items {
+        if let MqttMessageV3::Subscribe(msg) = item {
+            debug!("{:?}", msg);
+            message_id = msg.message_id;
+            let topic = &msg.topic;
+            if let Err(e) = topic::validate_filter(topic, &limits) {
+                info!("client {:?} subscribe invalid topic filter {:?}: {}", line.get_client_id(), topic, e);
+                codes.push(MqttQos::Failure);
+                continue;
+            }
+            let options = SubscriptionOptions::new(msg.qos);
+            SUBSCRIPT.subscript(topic, line.get_client_id(), Subscriber::new(line.get_sender(), options)).await;
+            line.session_mut().subscribe(topic, options);
+            debug!("broadcast client len: {:?}", SUBSCRIPT.client_len(topic).await);
+            codes.push(msg.qos);
+            retain_messages.extend(get_retain_messages(line, topic, options).await);
+        }
     }
-    debug!("broadcast topic len: {}", SUBSCRIPT.len().await);
-    debug!("broadcast topic list: {:?}", SUBSCRIPT.topics().await);
-    debug!("broadcast client len: {:?}", SUBSCRIPT.client_len(topic).await);
-    debug!("broadcast client list: {:?}", SUBSCRIPT.clients(topic).await);
-    let sm = SubackMessage::from(msg.clone());
+    let sm = SubackMessage::from_codes(message_id, codes);
     debug!("{:?}", sm);
-    return Some(MqttMessageV3::Suback(sm));
+    let mut res = sm.as_bytes().to_vec();
+    res.extend(retain_messages.concat());
+    MqttMessageKind::Response(res)
 }
 
-async fn handle_v3_unsubscribe(line: &mut Line, msg: &UnsubscribeMessage) -> Option<MqttMessageV3> {
-    debug!("topic name: {}", &msg.topic);
-    if SUBSCRIPT.contain(&msg.topic).await {
-        if SUBSCRIPT.is_subscript(&msg.topic, line.get_client_id()).await {
-            SUBSCRIPT.unsubscript(&msg.topic, line.get_client_id()).await;
-            return Some(MqttMessageV3::Unsuback(UnsubackMessage::new(msg.message_id)));
+///
+/// 一个 UNSUBSCRIBE 报文中的所有主题过滤器，无论是否订阅过都回复一个 UNSUBACK
+///
+async fn handle_v3_unsubscribe(line: &mut Line, items: &[MqttMessageV3]) -> MqttMessageKind {
+    let limits = CONFIG.get_mqtt_topic_limits();
+    let mut message_id = 0;
+    for item in items {
+        if let MqttMessageV3::Unsubscribe(msg) = item {
+            debug!("topic name: {}", &msg.topic);
+            message_id = msg.message_id;
+            if let Err(e) = topic::validate_filter(&msg.topic, &limits) {
+                info!("client {:?} unsubscribe invalid topic filter {:?}: {}", line.get_client_id(), msg.topic, e);
+                continue;
+            }
+            if SUBSCRIPT.is_subscript(&msg.topic, line.get_client_id()).await {
+                SUBSCRIPT.unsubscript(&msg.topic, line.get_client_id()).await;
+            }
+            line.session_mut().unsubscribe(&msg.topic);
         }
     }
-    return None;
+    MqttMessageKind::Response(UnsubackMessage::new(message_id).into_vec())
 }
 
 async fn handle_v3_disconnect(line: &mut Line) -> Option<MqttMessageV3> {
-    info!("client disconnect");
-    if line.is_will_flag() {
-        let topic_msg = line.get_v3_topic_message();
-        SUBSCRIPT.broadcast(line.get_will_topic(), &topic_msg).await;
-    }
-    SUBSCRIPT.exit(line.get_client_id()).await;
-    let id = MachineID(line.get_client_id().as_string());
-    MACHINE_CONTAINER.remove(&id).await;
-    return Some(MqttMessageV3::Disconnect(DisconnectMessage::default()));
+    info!("client {:?} disconnect", line.get_client_id());
+    line.clear_will();
+    Some(MqttMessageV3::Disconnect(DisconnectMessage::default()))
 }
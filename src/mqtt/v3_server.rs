@@ -1,15 +1,36 @@
-use crate::mqtt::message::v3::{PublishMessage, ConnectMessage};
+use crate::mqtt::message::v3::ConnectMessage;
 use crate::mqtt::message::{BaseConnect, BaseMessage};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use std::collections::HashMap;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Sender, Receiver};
-use crate::mqtt::tools::protocol::{MqttProtocolLevel, MqttWillFlag, MqttQos, MqttRetain, MqttDup};
+use tokio::sync::mpsc::error::TrySendError;
+use crate::mqtt::tools::protocol::{MqttProtocolLevel, MqttCleanSession, MqttWillFlag, MqttQos, MqttRetain, MqttSessionPresent, MqttNoLocal, MqttRetainAsPublished, MqttRetainHandling};
+use crate::mqtt::tools::error::DecodeError;
+use std::convert::TryFrom;
 use crate::mqtt::message::{MqttMessageKind, MqttMessage, MqttBytesMessage};
 use crate::mqtt::tools::types::TypeKind;
 use crate::mqtt::v3_handle;
-use log::{debug, error};
+use crate::mqtt::v5_handle;
+use crate::mqtt::tools::topic::{TopicTree, split_shared};
+use log::{debug, error, info};
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::oneshot;
+use tokio::time;
+use crate::mqtt::client::{Connection, next_connection_id, assign_client_id};
+use crate::mqtt::hex::{Property, PropertyItem, PropertyValue, find_property};
+use crate::mqtt::tools::topic_alias::InboundAliases;
+use crate::mqtt::hex::reason_code::{ReasonPhrases, ReasonCodeV3};
+use crate::mqtt::session::Session;
+use crate::mqtt::message::application::ApplicationMessage;
+use crate::{CONFIG, SUBSCRIPT, RETAIN, SESSIONS, CLIENTS, MACHINE_CONTAINER, MachineID, Machine, MachineStatus};
+
+///
+/// 等待被接管的旧连接关闭的最长时间
+///
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone, Eq, Hash)]
 pub struct ClientID(pub String);
@@ -57,28 +78,63 @@ impl PartialEq for ClientID {
 
 #[derive(Debug, Clone)]
 pub enum TopicMessage {
-    ContentV3(ClientID, crate::mqtt::message::v3::PublishMessage),
-    ContentV5(ClientID, crate::mqtt::message::v5::PublishMessage),
-    Will(PublishMessage),
+    Content(ClientID, ApplicationMessage),
 }
 
 impl TopicMessage {
-    pub fn get_topic(&self) -> Option<&String> {
-        match self {
-            TopicMessage::ContentV3(_, msg) => { Some(&msg.topic) }
-            TopicMessage::ContentV5(_, msg) => { Some(&msg.topic) }
-            TopicMessage::Will(_) => { None }
+    pub fn get_topic(&self) -> &String {
+        let TopicMessage::Content(_, msg) = self;
+        &msg.topic
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let TopicMessage::Content(_, msg) = self;
+        msg.is_expired()
+    }
+
+    pub fn with_subscription_identifiers(&self, identifiers: &[u32]) -> TopicMessage {
+        let TopicMessage::Content(from, msg) = self;
+        TopicMessage::Content(from.clone(), msg.with_subscription_identifiers(identifiers))
+    }
+}
+
+///
+/// 发布消息：retain 为 1 时更新保留消息（消息体为空表示删除），再转发给已有的订阅者，
+/// 转发时的 retain 标志由订阅的 Retain As Published 选项决定，返回匹配到的订阅者数量
+///
+pub async fn publish(from: ClientID, msg: ApplicationMessage) -> usize {
+    if msg.retain == MqttRetain::Enable {
+        if msg.payload.is_empty() {
+            RETAIN.remove(&msg.topic).await;
+        } else {
+            RETAIN.set(msg.topic.clone(), TopicMessage::Content(from.clone(), msg.clone())).await;
         }
     }
+    let topic_msg = TopicMessage::Content(from, msg);
+    debug!("topic: {:?}", topic_msg);
+    SUBSCRIPT.broadcast(topic_msg.get_topic(), &topic_msg).await
 }
 
+///
+/// 会话过期后删除缓存的消息和订阅，客户端在此之前重连时会话不会被删除
+///
+async fn reap_session(client_id: ClientID) {
+    if SESSIONS.expire(&client_id).await.is_some() {
+        info!("client {:?} session expired", client_id);
+        SUBSCRIPT.exit(&client_id).await;
+    }
+}
+
+///
+/// 订阅关系，按主题层级组织，发布时根据通配符匹配订阅者
+///
 pub struct Subscript {
-    container: Arc<Mutex<HashMap<String, Topic>>>,
+    container: Arc<Mutex<TopicTree<Topic>>>,
 }
 
 impl Subscript {
     pub fn new() -> Subscript {
-        Subscript { container: Arc::new(Mutex::new(HashMap::default())) }
+        Subscript { container: Arc::new(Mutex::new(TopicTree::new())) }
     }
 
     pub async fn contain<S: AsRef<str>>(&self, topic_name: S) -> bool {
@@ -98,81 +154,400 @@ impl Subscript {
     }
 
     pub async fn is_subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> bool {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().contain(client_id)
+        let (share_name, filter) = split_filter(topic_name.as_ref());
+        match self.container.lock().await.get(filter) {
+            Some(topic) => match share_name {
+                Some(share_name) => topic.is_member(share_name, client_id),
+                None => topic.contain(client_id),
+            },
+            None => false
+        }
     }
 
-    pub async fn new_subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, sender: Sender<LineMessage>) {
+    pub async fn new_subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, subscriber: Subscriber) {
         let mut top = Topic::new(topic_name.as_ref());
-        top.subscript(client_id.as_ref(), sender);
+        top.subscript(client_id.as_ref(), subscriber);
         self.add(topic_name.as_ref(), top).await;
     }
 
-    pub fn subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, sender: Sender<LineMessage>) {
-        match self.container.try_lock() {
-            Ok(mut container) => {
-                if let Some(t) = container.get_mut(topic_name.as_ref()) {
-                    t.subscript(client_id.as_ref(), sender);
-                }
-            }
-            Err(e) => {
-                error!("{:?}", e)
+    ///
+    /// 订阅主题过滤器，共享订阅 `$share/{ShareName}/{filter}` 加入 filter 下对应的共享组
+    ///
+    pub async fn subscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS, subscriber: Subscriber) {
+        let (share_name, filter) = split_filter(topic_name.as_ref());
+        let mut container = self.container.lock().await;
+        if !container.contains_key(filter) {
+            container.insert(filter, Topic::new(filter));
+        }
+        if let Some(topic) = container.get_mut(filter) {
+            match share_name {
+                Some(share_name) => topic.join(share_name, client_id.as_ref(), subscriber),
+                None => topic.subscript(client_id.as_ref(), subscriber),
             }
         }
     }
 
     pub async fn unsubscript<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) {
-        self.container.lock().await.get_mut(topic_name.as_ref()).unwrap().unsubscript(client_id);
+        let (share_name, filter) = split_filter(topic_name.as_ref());
+        let mut container = self.container.lock().await;
+        let is_empty = match container.get_mut(filter) {
+            Some(topic) => {
+                match share_name {
+                    Some(share_name) => { topic.leave(share_name, client_id.as_ref()); }
+                    None => { topic.unsubscript(client_id); }
+                }
+                topic.client_len() == 0
+            }
+            None => false
+        };
+        if is_empty {
+            container.remove(filter);
+        }
     }
 
     pub async fn exit<S: AsRef<ClientID>>(&self, client_id: S) {
-        for (_, topic) in self.container.lock().await.iter_mut() {
-            topic.unsubscript(client_id.as_ref());
+        self.remove_client(client_id.as_ref(), true).await;
+    }
+
+    ///
+    /// 客户端断开后不再参与共享订阅的分发，clean_session 为 0 时重连会重新加入
+    ///
+    pub async fn leave_shared<S: AsRef<ClientID>>(&self, client_id: S) {
+        self.remove_client(client_id.as_ref(), false).await;
+    }
+
+    async fn remove_client(&self, client_id: &ClientID, include_direct: bool) {
+        let mut container = self.container.lock().await;
+        let mut empty_topics = vec![];
+        for topic in container.values_mut() {
+            if include_direct {
+                topic.unsubscript(client_id);
+            }
+            topic.leave_all(client_id);
+            if topic.client_len() == 0 {
+                empty_topics.push(topic.name.clone());
+            }
+        }
+        for topic_name in empty_topics {
+            container.remove(topic_name);
         }
     }
 
     pub async fn topics(&self) -> Vec<String> {
-        self.container.lock().await.keys().cloned().collect::<Vec<String>>()
+        self.container.lock().await.values().iter().map(|topic| topic.name.clone()).collect::<Vec<String>>()
     }
 
     pub async fn clients<S: AsRef<str>>(&self, topic_name: S) -> Vec<ClientID> {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().client_id_list()
+        self.container.lock().await.get(topic_name.as_ref()).map(|topic| topic.client_id_list()).unwrap_or_default()
     }
 
     pub async fn client_len<S: AsRef<str>>(&self, topic_name: S) -> usize {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().client_len()
+        self.container.lock().await.get(topic_name.as_ref()).map(|topic| topic.client_len()).unwrap_or(0)
+    }
+
+    ///
+    /// 向所有匹配主题名的订阅者发送消息，同一客户端通过多个过滤器匹配时只发送一次，
+    /// 合并这些订阅的选项并附带所有匹配的订阅标识符；设置了 No Local 的订阅不会收到自己发布的消息；
+    /// 每个匹配的共享组只投递给组内的一个成员
+    ///
+    pub async fn broadcast<S: AsRef<str>>(&self, topic_name: S, msg: &TopicMessage) -> usize {
+        let TopicMessage::Content(from, _) = msg;
+        let mut subscribers: HashMap<ClientID, (Subscriber, Vec<u32>)> = HashMap::new();
+        let mut groups = vec![];
+        for topic in self.container.lock().await.matches(topic_name.as_ref()) {
+            for (client_id, subscriber) in topic.senders.iter() {
+                if subscriber.options.no_local == MqttNoLocal::Enable && client_id == from {
+                    continue;
+                }
+                let (_, identifiers) = subscribers.entry(client_id.clone())
+                    .and_modify(|(item, _)| item.options = item.options.merge(subscriber.options))
+                    .or_insert_with(|| (subscriber.clone(), vec![]));
+                identifiers.extend(subscriber.options.subscription_identifier);
+            }
+            for (share_name, group) in topic.shared.iter() {
+                groups.push((shared_filter(share_name, &topic.name), group.candidates()));
+            }
+        }
+        let mut matched = subscribers.len();
+        for (filter, candidates) in groups {
+            if deliver_shared(&filter, candidates, msg).await {
+                matched += 1;
+            }
+        }
+        for (client_id, (subscriber, identifiers)) in subscribers {
+            let msg = msg.with_subscription_identifiers(&identifiers);
+            match subscriber.sender.try_send(LineMessage::SubscriptionMessage(msg.clone(), subscriber.options)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => deliver_later(client_id, subscriber, msg),
+                // 连接已经断开，clean_session 为 0 的会话会缓存消息等待重连
+                Err(TrySendError::Closed(_)) => enqueue_offline(&client_id, &msg, subscriber.options).await,
+            }
+        }
+        matched
+    }
+
+    ///
+    /// 将消息投递给共享订阅组内的一个成员，用于成员断开后重新分发未确认的消息
+    ///
+    pub async fn share<S: AsRef<str>>(&self, shared_filter: S, msg: &TopicMessage) -> bool {
+        let candidates = match split_filter(shared_filter.as_ref()) {
+            (Some(share_name), filter) => {
+                let container = self.container.lock().await;
+                match container.get(filter).and_then(|topic| topic.shared.get(share_name)) {
+                    Some(group) => group.candidates(),
+                    None => vec![],
+                }
+            }
+            (None, _) => vec![],
+        };
+        deliver_shared(shared_filter.as_ref(), candidates, msg).await
+    }
+
+    pub async fn get_client<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> Option<Subscriber> {
+        self.container.lock().await.get(topic_name.as_ref())?.senders.get(client_id.as_ref()).cloned()
+    }
+}
+
+///
+/// 拆分订阅用的过滤器，返回共享名及实际的主题过滤器
+///
+fn split_filter(topic_name: &str) -> (Option<&str>, &str) {
+    match split_shared(topic_name) {
+        Some((share_name, filter)) => (Some(share_name), filter),
+        None => (None, topic_name),
     }
+}
+
+fn shared_filter(share_name: &str, filter: &str) -> String {
+    format!("$share/{}/{}", share_name, filter)
+}
 
-    pub async fn broadcast<S: AsRef<str>>(&self, topic_name: S, msg: &TopicMessage) {
-        if let Some(t) = self.container.lock().await.get(topic_name.as_ref()) {
-            t.broadcast(msg).await
+///
+/// 按顺序尝试共享组的成员，投递给第一个能立即接收的成员；
+/// 在线成员的通道都已满时，QoS 1/2 的消息交给单独的任务等待第一个在线成员接收
+///
+async fn deliver_shared(shared_filter: &str, candidates: Vec<(ClientID, Subscriber)>, msg: &TopicMessage) -> bool {
+    let mut busy = None;
+    for (client_id, subscriber) in candidates {
+        let identifiers = subscriber.options.subscription_identifier.into_iter().collect::<Vec<u32>>();
+        let line_msg = LineMessage::SharedMessage(msg.with_subscription_identifiers(&identifiers), subscriber.options, shared_filter.to_owned());
+        match subscriber.sender.try_send(line_msg) {
+            Ok(()) => return true,
+            Err(TrySendError::Full(line_msg)) => {
+                debug!("shared subscription member {:?} is busy", client_id);
+                busy = busy.or(Some((subscriber, line_msg)));
+            }
+            Err(TrySendError::Closed(_)) => debug!("shared subscription member {:?} is offline", client_id),
+        }
+    }
+    match busy {
+        Some((subscriber, line_msg)) if effective_qos(msg, subscriber.options) != MqttQos::Qos0 => {
+            let shared_filter = shared_filter.to_owned();
+            tokio::spawn(async move {
+                if subscriber.sender.send(line_msg).await.is_err() {
+                    debug!("no member of {} is online, message dropped", shared_filter);
+                }
+            });
+            true
+        }
+        Some(_) => {
+            debug!("all members of {} are busy, QoS 0 message dropped", shared_filter);
+            false
         }
+        None => {
+            debug!("no member of {} is online, message dropped", shared_filter);
+            false
+        }
+    }
+}
+
+///
+/// 订阅者的通道已满时不能在发布者的连接任务中等待，否则两个连接互相发布时会彼此阻塞；
+/// QoS 0 的消息直接丢弃，QoS 1/2 的消息交给单独的任务等待发送
+///
+fn deliver_later(client_id: ClientID, subscriber: Subscriber, msg: TopicMessage) {
+    if effective_qos(&msg, subscriber.options) == MqttQos::Qos0 {
+        debug!("client {:?} is busy, QoS 0 message dropped", client_id);
+        return;
     }
+    tokio::spawn(async move {
+        if subscriber.sender.send(LineMessage::SubscriptionMessage(msg.clone(), subscriber.options)).await.is_err() {
+            enqueue_offline(&client_id, &msg, subscriber.options).await;
+        }
+    });
+}
 
-    pub async fn get_client<S: AsRef<str>, SS: AsRef<ClientID>>(&self, topic_name: S, client_id: SS) -> Sender<LineMessage> {
-        self.container.lock().await.get(topic_name.as_ref()).unwrap().senders.get(client_id.as_ref()).unwrap().clone()
+async fn enqueue_offline(client_id: &ClientID, msg: &TopicMessage, options: SubscriptionOptions) {
+    if !SESSIONS.enqueue(client_id, msg, options, CONFIG.get_mqtt_max_queued_messages()).await {
+        debug!("client {:?} is offline, message dropped", client_id);
+    }
+}
+
+///
+/// 转发给订阅者时实际使用的 QoS
+///
+fn effective_qos(msg: &TopicMessage, options: SubscriptionOptions) -> MqttQos {
+    let TopicMessage::Content(_, content) = msg;
+    std::cmp::min(content.qos, options.qos)
+}
+
+///
+/// 通知旧连接关闭并等待其清理完成
+///
+async fn close_previous(client_id: &ClientID, previous: &Connection) {
+    let (done, wait) = oneshot::channel();
+    if previous.sender.send(LineMessage::Takeover(done)).await.is_ok()
+        && time::timeout(TAKEOVER_TIMEOUT, wait).await.is_err() {
+        error!("client {:?} previous connection did not close in time", client_id);
+    }
+}
+
+///
+/// 订阅选项，v3 的订阅只有 QoS，其它选项使用默认值
+///
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SubscriptionOptions {
+    pub qos: MqttQos,
+    /// 不接收自己发布的消息
+    pub no_local: MqttNoLocal,
+    /// 转发时保留发布者设置的 retain 标志
+    pub retain_as_published: MqttRetainAsPublished,
+    pub retain_handling: MqttRetainHandling,
+    /// v5 订阅时携带的订阅标识符，转发匹配的消息时附带给订阅者
+    pub subscription_identifier: Option<u32>,
+}
+
+impl SubscriptionOptions {
+    pub fn new(qos: MqttQos) -> SubscriptionOptions {
+        SubscriptionOptions {
+            qos,
+            no_local: MqttNoLocal::Disable,
+            retain_as_published: MqttRetainAsPublished::Disable,
+            retain_handling: MqttRetainHandling::SendOnSubscribe,
+            subscription_identifier: None,
+        }
+    }
+
+    ///
+    /// 同一客户端通过多个订阅匹配时只发送一次，使用最大的 QoS，任一订阅要求时保留 retain 标志
+    ///
+    pub fn merge(self, other: SubscriptionOptions) -> SubscriptionOptions {
+        SubscriptionOptions {
+            qos: std::cmp::max(self.qos, other.qos),
+            retain_as_published: std::cmp::max(self.retain_as_published, other.retain_as_published),
+            ..self
+        }
+    }
+
+    ///
+    /// 按订阅选项生成转发给订阅者的消息
+    ///
+    pub fn forward(&self, msg: &ApplicationMessage) -> ApplicationMessage {
+        let retain = match self.retain_as_published {
+            MqttRetainAsPublished::Enable => msg.retain,
+            MqttRetainAsPublished::Disable => MqttRetain::Disable,
+        };
+        msg.forward(self.qos, retain)
+    }
+}
+
+impl From<&crate::mqtt::message::v5::SubscribeMessage> for SubscriptionOptions {
+    fn from(msg: &crate::mqtt::message::v5::SubscribeMessage) -> Self {
+        SubscriptionOptions {
+            qos: msg.qos.unwrap_or(MqttQos::Qos0),
+            no_local: msg.no_local.unwrap_or(MqttNoLocal::Disable),
+            retain_as_published: msg.retain_as_published.unwrap_or(MqttRetainAsPublished::Disable),
+            retain_handling: msg.retain_handling.and_then(|value| MqttRetainHandling::try_from(value).ok()).unwrap_or(MqttRetainHandling::SendOnSubscribe),
+            subscription_identifier: msg.properties.as_deref()
+                .and_then(|properties| find_property(properties, Property::SubscriptionIdentifier))
+                .and_then(PropertyItem::as_long),
+        }
+    }
+}
+
+///
+/// 订阅者：连接的消息通道及订阅选项
+///
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub sender: Sender<LineMessage>,
+    pub options: SubscriptionOptions,
+}
+
+impl Subscriber {
+    pub fn new(sender: Sender<LineMessage>, options: SubscriptionOptions) -> Subscriber {
+        Subscriber { sender, options }
+    }
+}
+
+///
+/// 共享订阅组，组内成员按加入顺序轮流接收消息
+///
+#[derive(Debug, Default)]
+pub struct SharedGroup {
+    members: Vec<(ClientID, Subscriber)>,
+    next: AtomicUsize,
+}
+
+impl SharedGroup {
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn join(&mut self, client_id: ClientID, subscriber: Subscriber) {
+        match self.members.iter_mut().find(|(id, _)| id == &client_id) {
+            Some(member) => member.1 = subscriber,
+            None => self.members.push((client_id, subscriber)),
+        }
+    }
+
+    pub fn leave(&mut self, client_id: &ClientID) -> Option<Subscriber> {
+        let index = self.members.iter().position(|(id, _)| id == client_id)?;
+        Some(self.members.remove(index).1)
+    }
+
+    pub fn contain(&self, client_id: &ClientID) -> bool {
+        self.members.iter().any(|(id, _)| id == client_id)
+    }
+
+    ///
+    /// 从轮到的成员开始排列所有成员，前面的成员投递失败时依次尝试后面的成员
+    ///
+    pub fn candidates(&self) -> Vec<(ClientID, Subscriber)> {
+        if self.members.is_empty() {
+            return vec![];
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+        self.members[start..].iter().chain(self.members[..start].iter()).cloned().collect::<Vec<(ClientID, Subscriber)>>()
     }
 }
 
 #[derive(Debug)]
 pub struct Topic {
     name: String,
-    senders: HashMap<ClientID, Sender<LineMessage>>,
+    senders: HashMap<ClientID, Subscriber>,
+    /// 共享名对应的共享订阅组
+    shared: HashMap<String, SharedGroup>,
 }
 
 impl Topic {
     pub fn new<S: Into<String>>(name: S) -> Topic {
-        Topic { name: name.into(), senders: HashMap::new() }
+        Topic { name: name.into(), senders: HashMap::new(), shared: HashMap::new() }
     }
 }
 
 impl Topic {
-    pub fn subscript<S: Into<ClientID>>(&mut self, client_id: S, sender: Sender<LineMessage>) {
+    pub fn subscript<S: Into<ClientID>>(&mut self, client_id: S, subscriber: Subscriber) {
         let id = client_id.into();
         debug!("subscript client id: {:?}", &id);
-        self.senders.insert(id, sender);
+        self.senders.insert(id, subscriber);
     }
 
-    pub fn unsubscript<S: AsRef<ClientID>>(&mut self, client_id: S) -> Option<Sender<LineMessage>> {
+    pub fn unsubscript<S: AsRef<ClientID>>(&mut self, client_id: S) -> Option<Subscriber> {
         if self.senders.contains_key(client_id.as_ref()) {
             return self.senders.remove(client_id.as_ref());
         }
@@ -183,14 +558,40 @@ impl Topic {
         self.senders.keys().cloned().collect::<Vec<ClientID>>()
     }
 
-    pub fn client_len(&self) -> usize {
-        self.senders.len()
+    pub fn join<S: Into<ClientID>>(&mut self, share_name: &str, client_id: S, subscriber: Subscriber) {
+        let id = client_id.into();
+        debug!("client id {:?} join shared group {}", &id, share_name);
+        self.shared.entry(share_name.to_owned()).or_default().join(id, subscriber);
+    }
+
+    pub fn leave<S: AsRef<ClientID>>(&mut self, share_name: &str, client_id: S) -> Option<Subscriber> {
+        let group = self.shared.get_mut(share_name)?;
+        let subscriber = group.leave(client_id.as_ref());
+        if group.is_empty() {
+            self.shared.remove(share_name);
+        }
+        subscriber
     }
 
-    pub async fn broadcast(&self, msg: &TopicMessage) {
-        for (_, sender) in self.senders.iter() {
-            sender.send(LineMessage::SubscriptionMessage(msg.clone())).await.expect("broadcast async message error");
+    ///
+    /// 退出所有共享组
+    ///
+    pub fn leave_all<S: AsRef<ClientID>>(&mut self, client_id: S) {
+        for group in self.shared.values_mut() {
+            group.leave(client_id.as_ref());
         }
+        self.shared.retain(|_, group| !group.is_empty());
+    }
+
+    pub fn is_member<S: AsRef<ClientID>>(&self, share_name: &str, client_id: S) -> bool {
+        self.shared.get(share_name).map(|group| group.contain(client_id.as_ref())).unwrap_or(false)
+    }
+
+    ///
+    /// 订阅者数量，包括共享组的成员
+    ///
+    pub fn client_len(&self) -> usize {
+        self.senders.len() + self.shared.values().map(SharedGroup::len).sum::<usize>()
     }
 
     pub fn contain<S: AsRef<ClientID>>(&self, client_id: S) -> bool {
@@ -198,32 +599,52 @@ impl Topic {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum LineMessage {
     SocketMessage(Vec<u8>),
-    SubscriptionMessage(TopicMessage)
+    SubscriptionMessage(TopicMessage, SubscriptionOptions),
+    /// 通过共享订阅投递的消息，附带共享订阅的过滤器
+    SharedMessage(TopicMessage, SubscriptionOptions, String),
+    /// 同一客户端标识的新连接接管会话，旧连接关闭后通过 oneshot 通知
+    Takeover(oneshot::Sender<()>),
 }
 
 pub struct Line {
+    connection_id: u64,
     sender: Sender<LineMessage>,
     receiver: Receiver<LineMessage>,
     client_id: Option<ClientID>,
+    assigned_client_id: bool,
     protocol_name: Option<String>,
     protocol_level: Option<MqttProtocolLevel>,
     will_flag: Option<MqttWillFlag>,
     will_qos: Option<MqttQos>,
     will_retain: Option<MqttRetain>,
     will_topic: Option<String>,
-    will_message: Option<String>,
+    will_message: Option<Vec<u8>>,
+    will_properties: Vec<PropertyItem>,
+    keep_alive: Option<u16>,
+    clean_session: Option<MqttCleanSession>,
+    /// 断开连接后会话保留的时间（秒），0 表示随连接结束，0xFFFFFFFF 表示永不过期
+    session_expiry_interval: u32,
+    /// 客户端在 CONNECT 中请求的会话过期时间，没有经过服务端上限的调整
+    requested_session_expiry_interval: u32,
+    session: Session,
+    /// 客户端允许服务端使用的主题别名数量
+    topic_alias_maximum: u16,
+    inbound_aliases: InboundAliases,
+    takeover: Option<oneshot::Sender<()>>,
 }
 
 impl Line {
     pub fn new() -> Line {
         let (sender, receiver) = mpsc::channel(128);
         Line {
+            connection_id: next_connection_id(),
             sender,
             receiver,
             client_id: None,
+            assigned_client_id: false,
             protocol_name: None,
             protocol_level: None,
             will_flag: None,
@@ -231,6 +652,15 @@ impl Line {
             will_retain: None,
             will_topic: None,
             will_message: None,
+            will_properties: vec![],
+            keep_alive: None,
+            clean_session: None,
+            session_expiry_interval: 0,
+            requested_session_expiry_interval: 0,
+            session: Session::new(),
+            topic_alias_maximum: 0,
+            inbound_aliases: InboundAliases::default(),
+            takeover: None,
         }
     }
 
@@ -238,6 +668,30 @@ impl Line {
         self.client_id.as_ref().unwrap()
     }
 
+    ///
+    /// 客户端标识为空时由服务端分配
+    ///
+    fn init_client_id(&mut self, client_id: &str) {
+        if client_id.is_empty() {
+            let assigned = assign_client_id(self.connection_id);
+            info!("assigned client id {:?}", assigned);
+            self.client_id = Some(assigned);
+            self.assigned_client_id = true;
+        } else {
+            self.client_id = Some(ClientID::from(client_id));
+        }
+    }
+
+    ///
+    /// 服务端分配了客户端标识时，v5 的 CONNACK 需要携带 AssignedClientIdentifier 属性
+    ///
+    pub fn assigned_client_identifier(&self) -> Option<PropertyItem> {
+        match self.client_id {
+            Some(ref client_id) if self.assigned_client_id => Some(PropertyItem(Property::AssignedClientIdentifier, PropertyValue::String(client_id.as_string()))),
+            _ => None
+        }
+    }
+
     pub fn init_protocol(&mut self, protocol_name: String, protocol_level: MqttProtocolLevel) {
         self.protocol_name = Some(protocol_name);
         self.protocol_level = Some(protocol_level);
@@ -247,95 +701,577 @@ impl Line {
         self.will_flag.unwrap() == MqttWillFlag::Enable
     }
 
-    pub fn get_v3_topic_message(&self) -> TopicMessage {
-        let msg = PublishMessage::new(
-            self.will_qos.unwrap(),
-            MqttDup::Disable,
-            self.will_retain.unwrap(),
+    pub fn is_clean_session(&self) -> bool {
+        self.clean_session != Some(MqttCleanSession::Disable)
+    }
+
+    pub fn session_expiry_interval(&self) -> u32 {
+        self.session_expiry_interval
+    }
+
+    ///
+    /// v5 客户端在 DISCONNECT 中更新会话过期时间，CONNECT 中为 0 时不能再改为非 0
+    ///
+    pub fn update_session_expiry(&mut self, interval: u32) -> bool {
+        if self.requested_session_expiry_interval == 0 && interval != 0 {
+            return false;
+        }
+        self.session_expiry_interval = interval.min(CONFIG.get_mqtt_max_session_expiry_interval());
+        true
+    }
+
+    ///
+    /// 遗嘱消息的有效时间从发布时开始计算
+    ///
+    pub fn get_will_message(&self) -> ApplicationMessage {
+        ApplicationMessage::new(
             self.will_topic.as_ref().unwrap().to_owned(),
-            0,
             self.will_message.as_ref().unwrap().to_owned(),
-        );
-        TopicMessage::ContentV3(self.get_client_id().clone(), msg)
+            self.will_qos.unwrap(),
+            self.will_retain.unwrap(),
+        ).with_properties(self.will_properties.clone())
+    }
+
+    pub fn get_topic_message(&self) -> TopicMessage {
+        TopicMessage::Content(self.get_client_id().clone(), self.get_will_message())
     }
 
     pub fn get_will_topic(&self) -> &String {
         self.will_topic.as_ref().unwrap()
     }
 
+    ///
+    /// 客户端正常断开时丢弃遗嘱消息
+    ///
+    pub fn clear_will(&mut self) {
+        self.will_flag = Some(MqttWillFlag::Disable);
+        self.will_topic = None;
+        self.will_message = None;
+        self.will_properties.clear();
+    }
+
+    ///
+    /// 发布遗嘱消息，will_retain 为 1 时同时写入保留消息
+    ///
+    async fn publish_will(&self) {
+        publish(self.get_client_id().clone(), self.get_will_message()).await;
+    }
+
+    ///
+    /// 超过 keep_alive 的 1.5 倍时间没有收到客户端报文则断开连接，0 表示不检测
+    ///
+    pub fn keep_alive_timeout(&self) -> Option<Duration> {
+        match self.keep_alive {
+            Some(keep_alive) if keep_alive > 0 => Some(Duration::from_millis(keep_alive as u64 * 1500)),
+            _ => None
+        }
+    }
+
     pub fn init_v3(&mut self, connect_msg: &ConnectMessage) {
-        self.client_id = Some(ClientID(connect_msg.payload.client_id.to_owned()));
+        self.init_client_id(&connect_msg.payload.client_id);
         self.will_flag = Some(connect_msg.will_flag);
         self.will_qos = Some(connect_msg.will_qos);
         self.will_retain = Some(connect_msg.will_retain);
         self.will_topic = connect_msg.payload.will_topic.clone();
         self.will_message = connect_msg.payload.will_message.clone();
+        self.keep_alive = Some(connect_msg.keep_alive);
+        self.clean_session = Some(connect_msg.clean_session);
+        // v3 的 clean_session 为 0 时会话永不过期，仍然受服务端配置的上限约束
+        self.session_expiry_interval = if self.is_clean_session() { 0 } else { CONFIG.get_mqtt_max_session_expiry_interval() };
     }
 
     pub fn init_v5(&mut self, connect_msg: &crate::mqtt::message::v5::ConnectMessage) {
-        self.client_id = Some(ClientID(connect_msg.payload.client_id.to_owned()));
+        self.init_client_id(&connect_msg.payload.client_id);
         self.will_flag = Some(connect_msg.will_flag);
         self.will_qos = Some(connect_msg.will_qos);
         self.will_retain = Some(connect_msg.will_retain);
         self.will_topic = connect_msg.payload.will_topic.clone();
         self.will_message = connect_msg.payload.will_message.clone();
+        let properties = connect_msg.properties.as_deref().unwrap_or_default();
+        self.topic_alias_maximum = find_property(properties, Property::TopicAliasMaximum).and_then(PropertyItem::as_short).unwrap_or(0);
+        self.requested_session_expiry_interval = find_property(properties, Property::SessionExpiryInterval).and_then(PropertyItem::as_long).unwrap_or(0);
+        self.session_expiry_interval = self.requested_session_expiry_interval.min(CONFIG.get_mqtt_max_session_expiry_interval());
+        self.inbound_aliases = InboundAliases::new(CONFIG.get_mqtt_topic_alias_maximum());
+        // 遗嘱延迟只对服务端有效，其它遗嘱属性随遗嘱消息转发
+        self.will_properties = connect_msg.payload.properties.iter().flatten()
+            .filter(|item| item.0 != Property::WillDelayInterval)
+            .cloned()
+            .collect::<Vec<PropertyItem>>();
+        self.keep_alive = Some(connect_msg.keep_alive);
+        self.clean_session = Some(connect_msg.clean_session);
+    }
+
+    ///
+    /// 连接结束时的清理：移除订阅并将设备标记为离线，
+    /// 非正常断开（超时、读写错误、协议错误）时还会发布遗嘱消息
+    ///
+    pub async fn close(&mut self) {
+        let client_id = match self.client_id {
+            Some(ref client_id) => client_id.clone(),
+            None => return,
+        };
+        info!("client {:?} connection closed", client_id);
+        if self.is_will_flag() {
+            self.publish_will().await;
+        }
+        // 新连接等待接管超时后已经登记，订阅、会话和机器状态都归新连接所有，不能再按客户端标识清理
+        if !CLIENTS.is_current(&client_id, self.connection_id).await {
+            info!("client {:?} already taken over by another connection", client_id);
+            return;
+        }
+        self.leave_shared(&client_id).await;
+        if self.session_expiry_interval == 0 {
+            SUBSCRIPT.exit(&client_id).await;
+            self.drain_receiver(&client_id, false).await;
+        } else {
+            self.store_session(client_id.clone()).await;
+        }
+        MACHINE_CONTAINER.remove(&MachineID(client_id.as_string())).await;
+        CLIENTS.unregister(&client_id, self.connection_id).await;
+        if let Some(done) = self.takeover.take() {
+            let _ = done.send(());
+        }
+    }
+
+    ///
+    /// 将连接成功的客户端登记为在线的机器
+    ///
+    pub async fn register_machine(&self) {
+        let client_id = self.get_client_id().as_string();
+        MACHINE_CONTAINER.append(MachineID(client_id.clone()), Machine {
+            id: client_id,
+            qrcode_url: "".to_string(),
+            status: MachineStatus::Online,
+        }).await;
+    }
+
+    ///
+    /// 同一客户端标识已有连接时先关闭旧连接，等待其清理完成后再登记当前连接；
+    /// 旧连接在登记前一直是客户端标识的所有者，超时后才关闭的旧连接不会再清理新连接的状态
+    ///
+    pub async fn take_over(&self) {
+        let client_id = self.get_client_id().clone();
+        let previous = CLIENTS.get(&client_id).await;
+        if let Some(ref previous) = previous {
+            info!("client {:?} session taken over", client_id);
+            close_previous(&client_id, previous).await;
+        }
+        let displaced = CLIENTS.register(client_id.clone(), Connection::new(self.connection_id, self.get_sender())).await;
+        // 等待期间同一客户端标识又有其它连接登记
+        if let Some(displaced) = displaced.filter(|displaced| previous.as_ref().map(|previous| previous.id) != Some(displaced.id)) {
+            close_previous(&client_id, &displaced).await;
+        }
+    }
+
+    ///
+    /// 退出所有共享组，已经投递给当前连接但没有确认的 QoS 1 消息交给组内其它成员
+    ///
+    async fn leave_shared(&mut self, client_id: &ClientID) {
+        SUBSCRIPT.leave_shared(client_id).await;
+        for (shared_filter, msg) in self.session.take_shared() {
+            debug!("redeliver message on {} from {:?}", msg.topic, client_id);
+            SUBSCRIPT.share(shared_filter, &TopicMessage::Content(client_id.clone(), msg)).await;
+        }
+    }
+
+    ///
+    /// 保存会话，订阅保留在 SUBSCRIPT 中，之后发给该客户端的消息会缓存到会话里，
+    /// 还没有处理的共享订阅消息交给组内其它成员
+    ///
+    async fn store_session(&mut self, client_id: ClientID) {
+        let interval = self.session_expiry_interval;
+        self.session.set_expiry(interval);
+        SESSIONS.store(client_id.clone(), std::mem::take(&mut self.session)).await;
+        if interval != u32::MAX {
+            let client_id = client_id.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_secs(interval as u64)).await;
+                reap_session(client_id).await;
+            });
+        }
+        self.drain_receiver(&client_id, true).await;
+    }
+
+    ///
+    /// 关闭消息通道并处理还没有发出的消息：共享订阅消息交给组内其它成员，
+    /// 普通订阅消息在保存会话时缓存到会话里，否则丢弃
+    ///
+    async fn drain_receiver(&mut self, client_id: &ClientID, keep_session: bool) {
+        self.receiver.close();
+        while let Ok(msg) = self.receiver.try_recv() {
+            match msg {
+                LineMessage::SubscriptionMessage(msg, options) if keep_session => {
+                    SESSIONS.enqueue(client_id, &msg, options, CONFIG.get_mqtt_max_queued_messages()).await;
+                }
+                LineMessage::SharedMessage(msg, _, shared_filter) => {
+                    SUBSCRIPT.share(shared_filter, &msg).await;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    ///
+    /// CONNECT 之后初始化会话：clean_session 为 1 时丢弃旧会话，
+    /// 否则恢复保存的会话并把订阅指向当前连接，返回 CONNACK 的 session present 标志
+    ///
+    pub async fn init_session(&mut self) -> MqttSessionPresent {
+        let session_present = self.restore_session().await;
+        if let Some(level) = self.protocol_level {
+            self.session.attach(level, self.topic_alias_maximum);
+        }
+        session_present
+    }
+
+    async fn restore_session(&mut self) -> MqttSessionPresent {
+        let client_id = self.get_client_id().clone();
+        if self.is_clean_session() {
+            SESSIONS.take(&client_id).await;
+            SUBSCRIPT.exit(&client_id).await;
+            return MqttSessionPresent::Disable;
+        }
+        let subscriptions = match SESSIONS.subscriptions(&client_id).await {
+            Some(subscriptions) => subscriptions,
+            None => return MqttSessionPresent::Disable,
+        };
+        for (filter, options) in subscriptions {
+            SUBSCRIPT.subscript(filter, &client_id, Subscriber::new(self.get_sender(), options)).await;
+        }
+        match SESSIONS.take(&client_id).await {
+            Some(session) => {
+                self.session = session;
+                MqttSessionPresent::Enable
+            }
+            None => MqttSessionPresent::Disable
+        }
+    }
+
+    ///
+    /// 会话恢复后重发未确认的消息及离线期间缓存的消息
+    ///
+    pub fn resume_session(&mut self) -> Vec<u8> {
+        self.session.resume().concat()
     }
 
     pub fn get_sender(&self) -> Sender<LineMessage> {
         self.sender.clone()
     }
 
+    pub fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    pub fn inbound_aliases_mut(&mut self) -> &mut InboundAliases {
+        &mut self.inbound_aliases
+    }
+
+    ///
+    /// 发送给当前客户端的消息，QoS 1/2 会分配报文标识符并等待确认
+    ///
+    pub fn publish_to_client(&mut self, msg: &ApplicationMessage) -> Option<Vec<u8>> {
+        self.deliver(msg, None)
+    }
+
+    fn deliver(&mut self, msg: &ApplicationMessage, shared_filter: Option<&str>) -> Option<Vec<u8>> {
+        let publish = match shared_filter {
+            Some(shared_filter) => self.session.publish_shared(msg, shared_filter),
+            None => self.session.publish(msg),
+        };
+        match publish {
+            Some(publish) => Some(publish),
+            None => {
+                error!("client {:?} has no free packet identifier, message dropped", self.client_id);
+                None
+            }
+        }
+    }
+
+    ///
+    /// 重发超时未确认的 QoS 1/2 消息
+    ///
+    pub fn retry(&mut self) -> Option<MqttMessageKind> {
+        let resend = self.session.retry(Duration::from_secs(CONFIG.get_mqtt_retry_interval()));
+        if resend.is_empty() {
+            return None;
+        }
+        Some(MqttMessageKind::Response(resend.concat()))
+    }
+
     pub async fn recv(&mut self) -> Option<MqttMessageKind> {
         match self.receiver.recv().await {
             None => { None }
             Some(msg) => {
                 match msg {
                     LineMessage::SocketMessage(msg) => self.handle_socket_message(msg).await,
-                    LineMessage::SubscriptionMessage(msg) => self.handle_subscription_message(msg)
+                    LineMessage::SubscriptionMessage(msg, options) => self.handle_subscription_message(msg, options, None),
+                    LineMessage::SharedMessage(msg, options, shared_filter) => self.handle_subscription_message(msg, options, Some(&shared_filter)),
+                    LineMessage::Takeover(done) => {
+                        self.takeover = Some(done);
+                        Some(self.handle_takeover())
+                    }
                 }
             }
         }
     }
 
-    async fn handle_socket_message(&mut self, msg: Vec<u8>) -> Option<MqttMessageKind> {
-        let base_msg = BaseMessage::from(msg);
-        if base_msg.get_message_type() == TypeKind::CONNECT {
-            let connect = BaseConnect::from(&base_msg);
-            self.init_protocol(connect.get_protocol_name(), connect.get_protocol_level());
+    pub async fn handle_socket_message(&mut self, msg: Vec<u8>) -> Option<MqttMessageKind> {
+        let base_msg = match BaseMessage::try_from(msg) {
+            Ok(base_msg) => base_msg,
+            Err(e) => return Some(self.handle_decode_error(e)),
+        };
+        let is_connect = base_msg.get_message_type() == TypeKind::CONNECT;
+        // 第一个报文必须是 CONNECT，同一连接上重复的 CONNECT 视为协议错误
+        if is_connect == self.client_id.is_some() {
+            error!("client {:?} sent {:?} out of order", self.client_id, base_msg.get_message_type());
+            return Some(self.handle_protocol_error());
+        }
+        if is_connect {
+            match BaseConnect::try_from(&base_msg) {
+                Ok(connect) => {
+                    let level = connect.get_protocol_level();
+                    if connect.get_protocol_name() != level.protocol_name() {
+                        error!("protocol name {} does not match protocol level {:?}", connect.get_protocol_name(), level);
+                        return Some(self.reject_protocol(Some(level)));
+                    }
+                    self.init_protocol(connect.get_protocol_name(), level)
+                }
+                Err(DecodeError::UnsupportedProtocolLevel(level)) => {
+                    error!("unsupported protocol level: {}", level);
+                    return Some(self.reject_protocol(None));
+                }
+                Err(e) => return Some(self.handle_decode_error(e)),
+            }
         }
 
-        if let Some(level) = self.protocol_level {
-            return match level {
-                MqttProtocolLevel::Level3_1_1 => v3_handle::match_v3_data(self, base_msg).await,
-                _ => { return None; }
-            };
+        match self.protocol_level {
+            Some(MqttProtocolLevel::Level3_1) | Some(MqttProtocolLevel::Level3_1_1) => v3_handle::match_v3_data(self, base_msg).await,
+            Some(MqttProtocolLevel::Level5) => v5_handle::match_v5_data(self, base_msg).await,
+            None => None,
         }
-        None
     }
 
-    fn handle_subscription_message(&mut self, msg: TopicMessage) -> Option<MqttMessageKind> {
-        return match msg {
-            TopicMessage::ContentV3(from_id, content) => {
-                debug!("from: {:?}", from_id);
-                debug!("to: {:?}", self.get_client_id());
-                if self.get_client_id() != &from_id {
-                    return Some(MqttMessageKind::Response(content.as_bytes().to_vec()));
-                }
-                None
+    ///
+    /// 不支持的协议名或协议版本，v3 回复返回码 0x01 的 CONNACK，v5 回复原因码 0x84，然后关闭连接
+    ///
+    fn reject_protocol(&self, level: Option<MqttProtocolLevel>) -> MqttMessageKind {
+        match level {
+            Some(MqttProtocolLevel::Level5) => {
+                let msg = crate::mqtt::message::v5::ConnackMessage::new(MqttSessionPresent::Disable, ReasonPhrases::UnsupportedProtocolVersion, None);
+                MqttMessageKind::Exit(msg.into_vec())
             }
-            TopicMessage::ContentV5(from_id, content) => {
-                debug!("from: {:?}", from_id);
-                debug!("to: {:?}", self.get_client_id());
-                if self.get_client_id() != &from_id {
-                    return Some(MqttMessageKind::Response(content.as_bytes().to_vec()));
-                }
-                None
+            _ => {
+                let msg = crate::mqtt::message::v3::ConnackMessage::new(MqttSessionPresent::Disable, ReasonCodeV3::UnacceptableProtocolVersion);
+                MqttMessageKind::Exit(msg.into_vec())
+            }
+        }
+    }
+
+    ///
+    /// 违反报文顺序时关闭连接，已连接的 v5 客户端会收到原因码为 0x82 的 DISCONNECT
+    ///
+    pub fn handle_protocol_error(&self) -> MqttMessageKind {
+        match self.protocol_level {
+            Some(MqttProtocolLevel::Level5) => {
+                let msg = crate::mqtt::message::v5::DisconnectMessage::new(ReasonPhrases::ProtocolError, None);
+                MqttMessageKind::Exit(msg.into_vec())
             }
-            TopicMessage::Will(content) => {
-                Some(MqttMessageKind::Response(content.as_bytes().to_vec()))
+            _ => MqttMessageKind::Exit(vec![])
+        }
+    }
+
+    ///
+    /// 会话被新连接接管，v5 客户端会收到原因码为 0x8E 的 DISCONNECT
+    ///
+    fn handle_takeover(&self) -> MqttMessageKind {
+        match self.protocol_level {
+            Some(MqttProtocolLevel::Level5) => {
+                let msg = crate::mqtt::message::v5::DisconnectMessage::new(ReasonPhrases::SessionTakenOver, None);
+                MqttMessageKind::Exit(msg.into_vec())
             }
-        };
+            _ => MqttMessageKind::Exit(vec![])
+        }
+    }
+
+    ///
+    /// 报文解析失败时记录日志并关闭连接，v5 客户端会收到对应的原因码
+    ///
+    pub fn handle_decode_error(&self, e: DecodeError) -> MqttMessageKind {
+        error!("client {:?} sent a malformed packet: {}", self.client_id, e);
+        match self.protocol_level {
+            Some(MqttProtocolLevel::Level5) => {
+                if self.client_id.is_some() {
+                    let msg = crate::mqtt::message::v5::DisconnectMessage::new(e.as_reason_phrase(), None);
+                    MqttMessageKind::Exit(msg.into_vec())
+                } else {
+                    let msg = crate::mqtt::message::v5::ConnackMessage::new(MqttSessionPresent::Disable, e.as_reason_phrase(), None);
+                    MqttMessageKind::Exit(msg.into_vec())
+                }
+            }
+            _ => MqttMessageKind::Exit(vec![])
+        }
+    }
+
+    fn handle_subscription_message(&mut self, msg: TopicMessage, options: SubscriptionOptions, shared_filter: Option<&str>) -> Option<MqttMessageKind> {
+        let TopicMessage::Content(from_id, content) = msg;
+        debug!("from: {:?}", from_id);
+        debug!("to: {:?}", self.get_client_id());
+        if content.is_expired() {
+            debug!("message on {} expired", content.topic);
+            return None;
+        }
+        let publish = options.forward(&content);
+        self.deliver(&publish, shared_filter).map(MqttMessageKind::Response)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[tokio::test]
+    async fn test_shared_subscription() {
+        let subscript = Subscript::new();
+        let mut lines = vec![];
+        for id in ["a", "b"] {
+            let (sender, receiver) = mpsc::channel(8);
+            subscript.subscript("$share/workers/results/#", ClientID::from(id), Subscriber::new(sender, SubscriptionOptions::new(MqttQos::Qos1))).await;
+            lines.push(receiver);
+        }
+        assert!(subscript.is_subscript("$share/workers/results/#", ClientID::from("a")).await);
+        assert!(!subscript.is_subscript("results/#", ClientID::from("a")).await);
+
+        let msg = TopicMessage::Content(ClientID::from("c"), ApplicationMessage::new("results/1", b"ok".to_vec(), MqttQos::Qos1, MqttRetain::Disable));
+        assert_eq!(subscript.broadcast("results/1", &msg).await, 1);
+        assert_eq!(subscript.broadcast("results/2", &msg).await, 1);
+        let received = lines.iter_mut().map(|receiver| {
+            match receiver.try_recv() {
+                Ok(LineMessage::SharedMessage(_, _, filter)) => filter == "$share/workers/results/#",
+                _ => false
+            }
+        }).collect::<Vec<bool>>();
+        assert_eq!(received, vec![true, true]);
+
+        // 断开的成员不再接收消息
+        subscript.leave_shared(ClientID::from("a")).await;
+        assert_eq!(subscript.broadcast("results/3", &msg).await, 1);
+        assert!(lines[0].try_recv().is_err());
+        assert!(lines[1].try_recv().is_ok());
+
+        subscript.unsubscript("$share/workers/results/#", ClientID::from("b")).await;
+        assert_eq!(subscript.len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_clean_shared_member_close() {
+        let filter = "$share/clean/close/#";
+        let mut line = Line::new();
+        let client_id = ClientID::from("clean-shared-a");
+        line.client_id = Some(client_id.clone());
+        line.will_flag = Some(MqttWillFlag::Disable);
+        CLIENTS.register(client_id.clone(), Connection::new(line.connection_id, line.get_sender())).await;
+        SUBSCRIPT.subscript(filter, &client_id, Subscriber::new(line.get_sender(), SubscriptionOptions::new(MqttQos::Qos1))).await;
+        let (sender, mut receiver) = mpsc::channel(8);
+        SUBSCRIPT.subscript(filter, ClientID::from("clean-shared-b"), Subscriber::new(sender, SubscriptionOptions::new(MqttQos::Qos1))).await;
+
+        // 断开前已经投递给 a、还没有发出的共享订阅消息
+        let msg = TopicMessage::Content(ClientID::from("c"), ApplicationMessage::new("close/1", b"ok".to_vec(), MqttQos::Qos1, MqttRetain::Disable));
+        let sender = line.get_sender();
+        sender.try_send(LineMessage::SharedMessage(msg.clone(), SubscriptionOptions::new(MqttQos::Qos1), filter.to_owned())).unwrap();
+        sender.try_send(LineMessage::SubscriptionMessage(msg, SubscriptionOptions::new(MqttQos::Qos1))).unwrap();
+        line.close().await;
+
+        match receiver.try_recv() {
+            Ok(LineMessage::SharedMessage(_, _, shared_filter)) => assert_eq!(shared_filter, filter),
+            _ => panic!("expected shared message"),
+        }
+        assert!(receiver.try_recv().is_err());
+        assert!(sender.is_closed());
+        assert!(SESSIONS.take(&client_id).await.is_none());
+        SUBSCRIPT.exit(ClientID::from("clean-shared-b")).await;
+    }
+
+    #[tokio::test]
+    async fn test_subscription_options() {
+        let subscript = Subscript::new();
+        let (sender, mut receiver) = mpsc::channel(8);
+        let mut options = SubscriptionOptions::new(MqttQos::Qos1);
+        options.no_local = MqttNoLocal::Enable;
+        subscript.subscript("kiosk/#", ClientID::from("a"), Subscriber::new(sender.clone(), options)).await;
+
+        let msg = ApplicationMessage::new("kiosk/1", b"qr".to_vec(), MqttQos::Qos1, MqttRetain::Enable);
+        assert_eq!(subscript.broadcast("kiosk/1", &TopicMessage::Content(ClientID::from("a"), msg.clone())).await, 0);
+        assert_eq!(subscript.broadcast("kiosk/1", &TopicMessage::Content(ClientID::from("b"), msg.clone())).await, 1);
+        match receiver.try_recv() {
+            Ok(LineMessage::SubscriptionMessage(_, options)) => assert_eq!(options.forward(&msg).retain, MqttRetain::Disable),
+            _ => panic!("expected subscription message"),
+        }
+
+        // 重叠的订阅合并选项，没有设置 No Local 的订阅仍然会收到自己发布的消息
+        let mut options = SubscriptionOptions::new(MqttQos::Qos0);
+        options.retain_as_published = MqttRetainAsPublished::Enable;
+        subscript.subscript("kiosk/+", ClientID::from("a"), Subscriber::new(sender, options)).await;
+        assert_eq!(subscript.broadcast("kiosk/1", &TopicMessage::Content(ClientID::from("b"), msg.clone())).await, 1);
+        match receiver.try_recv() {
+            Ok(LineMessage::SubscriptionMessage(_, options)) => {
+                let forwarded = options.forward(&msg);
+                assert_eq!(forwarded.qos, MqttQos::Qos1);
+                assert_eq!(forwarded.retain, MqttRetain::Enable);
+            }
+            _ => panic!("expected subscription message"),
+        }
+        assert_eq!(subscript.broadcast("kiosk/1", &TopicMessage::Content(ClientID::from("a"), msg)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_subscription_identifiers() {
+        let subscript = Subscript::new();
+        let (sender, mut receiver) = mpsc::channel(8);
+        for (filter, identifier) in [("kiosk/#", 1), ("kiosk/+", 2)] {
+            let mut options = SubscriptionOptions::new(MqttQos::Qos1);
+            options.subscription_identifier = Some(identifier);
+            subscript.subscript(filter, ClientID::from("a"), Subscriber::new(sender.clone(), options)).await;
+        }
+        let identifiers = |msg: TopicMessage| {
+            let TopicMessage::Content(_, content) = msg;
+            content.properties.iter().filter_map(PropertyItem::as_long).collect::<Vec<u32>>()
+        };
+
+        let msg = TopicMessage::Content(ClientID::from("b"), ApplicationMessage::new("kiosk/1", b"qr".to_vec(), MqttQos::Qos1, MqttRetain::Disable));
+        subscript.broadcast("kiosk/1", &msg).await;
+        match receiver.try_recv() {
+            Ok(LineMessage::SubscriptionMessage(msg, _)) => {
+                let mut identifiers = identifiers(msg);
+                identifiers.sort_unstable();
+                assert_eq!(identifiers, vec![1, 2]);
+            }
+            _ => panic!("expected subscription message"),
+        }
+
+        subscript.unsubscript("kiosk/+", ClientID::from("a")).await;
+        subscript.broadcast("kiosk/1", &msg).await;
+        match receiver.try_recv() {
+            Ok(LineMessage::SubscriptionMessage(msg, _)) => assert_eq!(identifiers(msg), vec![1]),
+            _ => panic!("expected subscription message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_full_channel() {
+        let subscript = Subscript::new();
+        let (sender, mut receiver) = mpsc::channel(1);
+        subscript.subscript("kiosk/#", ClientID::from("a"), Subscriber::new(sender, SubscriptionOptions::new(MqttQos::Qos1))).await;
+        let qos0 = TopicMessage::Content(ClientID::from("a"), ApplicationMessage::new("kiosk/1", b"0".to_vec(), MqttQos::Qos0, MqttRetain::Disable));
+        let qos1 = TopicMessage::Content(ClientID::from("a"), ApplicationMessage::new("kiosk/1", b"1".to_vec(), MqttQos::Qos1, MqttRetain::Disable));
+        // 通道已满时发布者不会被阻塞，QoS 0 被丢弃，QoS 1 在通道空出后送达
+        subscript.broadcast("kiosk/1", &qos0).await;
+        subscript.broadcast("kiosk/1", &qos0).await;
+        subscript.broadcast("kiosk/1", &qos1).await;
+        let payloads = |msg: Option<LineMessage>| match msg {
+            Some(LineMessage::SubscriptionMessage(TopicMessage::Content(_, content), _)) => content.payload,
+            _ => panic!("expected subscription message"),
+        };
+        assert_eq!(payloads(receiver.recv().await), b"0".to_vec());
+        assert_eq!(payloads(receiver.recv().await), b"1".to_vec());
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::mqtt::v3_server::{Line, ClientID, Subscriber, SubscriptionOptions, publish};
+use crate::mqtt::message::application::ApplicationMessage;
+use crate::mqtt::message::{BaseMessage, MqttMessageKind, MqttBytesMessage};
+use crate::mqtt::message::v5::{MqttMessageV5, ConnackMessage, ConnectMessage, PublishMessage, CommonPayloadMessage, DisconnectMessage, SubackMessage, UnsubackMessage};
+use crate::mqtt::tools::protocol::{MqttQos, MqttSessionPresent, MqttNoLocal, MqttRetainHandling};
+use crate::mqtt::tools::types::TypeKind;
+use crate::mqtt::tools::topic;
+use crate::mqtt::hex::reason_code::ReasonPhrases;
+use crate::mqtt::hex::{Property, PropertyItem, PropertyValue, find_property};
+use crate::mqtt::client::{check_credentials, Credentials};
+use crate::mqtt::v3_handle::get_retain_messages;
+use crate::{CONFIG, SUBSCRIPT};
+use log::{debug, info};
+
+pub async fn match_v5_data(line: &mut Line, base_msg: BaseMessage) -> Option<MqttMessageKind> {
+    let v5 = match MqttMessageKind::v5(base_msg) {
+        Ok(Some(v5)) => v5,
+        Ok(None) => return None,
+        Err(e) => return Some(line.handle_decode_error(e)),
+    };
+    match v5 {
+        MqttMessageKind::RequestV5(kind) => handle_v5(line, kind).await,
+        MqttMessageKind::RequestsV5(items) => {
+            match items.first() {
+                Some(MqttMessageV5::Subscribe(_)) => Some(handle_v5_subscribe(line, &items).await),
+                Some(MqttMessageV5::Unsubscribe(_)) => Some(handle_v5_unsubscribe(line, &items).await),
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
+
+///
+/// 发布 v5 客户端的消息，返回匹配到的订阅者数量
+///
+pub async fn publish_v5(from: ClientID, msg: PublishMessage) -> usize {
+    publish(from, ApplicationMessage::from(&msg)).await
+}
+
+async fn handle_v5(line: &mut Line, kind: MqttMessageV5) -> Option<MqttMessageKind> {
+    match kind {
+        MqttMessageV5::Connect(msg) => Some(handle_v5_connect(line, &msg).await),
+        MqttMessageV5::Publish(msg) => handle_v5_publish(line, msg).await,
+        MqttMessageV5::Puback(msg) => {
+            if !line.session_mut().puback(msg.message_id) {
+                debug!("unknown puback message id: {}", msg.message_id);
+            }
+            None
+        }
+        MqttMessageV5::Pubrec(msg) => {
+            // 原因码大于等于 0x80 表示客户端不再接收该消息
+            if msg.code.as_byte() >= 0x80 {
+                info!("client {:?} rejected message {}: {}", line.get_client_id(), msg.message_id, msg.code.as_str());
+                line.session_mut().discard(msg.message_id);
+                return None;
+            }
+            let code = match line.session_mut().pubrec(msg.message_id) {
+                Some(_) => ReasonPhrases::Success,
+                None => ReasonPhrases::PacketIdentifierNotFound,
+            };
+            Some(MqttMessageKind::Response(CommonPayloadMessage::with_code(TypeKind::PUBREL, msg.message_id, code).into_vec()))
+        }
+        MqttMessageV5::Pubrel(msg) => {
+            let code = if line.session_mut().release_qos2(msg.message_id) {
+                ReasonPhrases::Success
+            } else {
+                debug!("unknown pubrel message id: {}", msg.message_id);
+                ReasonPhrases::PacketIdentifierNotFound
+            };
+            Some(MqttMessageKind::Response(CommonPayloadMessage::with_code(TypeKind::PUBCOMP, msg.message_id, code).into_vec()))
+        }
+        MqttMessageV5::Pubcomp(msg) => {
+            if !line.session_mut().pubcomp(msg.message_id) {
+                debug!("unknown pubcomp message id: {}", msg.message_id);
+            }
+            None
+        }
+        MqttMessageV5::Pingresp(msg) => Some(MqttMessageKind::Response(msg.into_vec())),
+        MqttMessageV5::Disconnect(msg) => Some(handle_v5_disconnect(line, &msg)),
+        MqttMessageV5::Auth(_) => {
+            // 没有实现增强认证，CONNECT 中也不会协商认证方法
+            info!("client {:?} sent unexpected auth", line.get_client_id());
+            Some(line.handle_protocol_error())
+        }
+        _ => None
+    }
+}
+
+///
+/// 检查 CONNECT 报文，返回拒绝连接时的原因码
+///
+fn check_v5_connect(msg: &ConnectMessage) -> Option<ReasonPhrases> {
+    if let Some(ref will_topic) = msg.payload.will_topic {
+        if let Err(e) = topic::validate_topic_name(will_topic, &CONFIG.get_mqtt_topic_limits()) {
+            info!("invalid will topic {:?}: {}", will_topic, e);
+            return Some(ReasonPhrases::TopicNameInvalid);
+        }
+    }
+    match check_credentials(msg.payload.user_name.as_deref(), msg.payload.password.as_deref()) {
+        Some(Credentials::Missing) => Some(ReasonPhrases::NotAuthorized),
+        Some(Credentials::Invalid) => Some(ReasonPhrases::BadUserNameOrPassword),
+        None => None
+    }
+}
+
+async fn handle_v5_connect(line: &mut Line, msg: &ConnectMessage) -> MqttMessageKind {
+    if let Some(code) = check_v5_connect(msg) {
+        info!("client {} connect rejected: {}", msg.payload.client_id, code.as_str());
+        return MqttMessageKind::Exit(ConnackMessage::new(MqttSessionPresent::Disable, code, None).into_vec());
+    }
+    line.init_v5(msg);
+    line.take_over().await;
+    line.register_machine().await;
+    let session_present = line.init_session().await;
+    let mut properties = ConnackMessage::server_properties(CONFIG.get_mqtt_topic_alias_maximum(), CONFIG.get_mqtt_max_packet_size());
+    properties.push(PropertyItem(Property::SessionExpiryInterval, PropertyValue::Long(line.session_expiry_interval())));
+    properties.extend(line.assigned_client_identifier());
+    let mut res = ConnackMessage::new(session_present, ReasonPhrases::Success, Some(properties)).into_vec();
+    res.extend(line.resume_session());
+    MqttMessageKind::Response(res)
+}
+
+///
+/// 收到 PUBLISH，QoS 1/2 回复的原因码表示是否有匹配的订阅者
+///
+async fn handle_v5_publish(line: &mut Line, mut msg: PublishMessage) -> Option<MqttMessageKind> {
+    let alias = msg.properties.as_deref().and_then(|properties| find_property(properties, Property::TopicAlias)).and_then(PropertyItem::as_short);
+    match line.inbound_aliases_mut().resolve(&msg.topic, alias) {
+        Ok(topic) => msg.topic = topic,
+        Err(code) => {
+            info!("client {:?} publish with invalid topic alias {:?}", line.get_client_id(), alias);
+            return Some(MqttMessageKind::Exit(DisconnectMessage::new(code, None).into_vec()));
+        }
+    }
+    if let Err(e) = topic::validate_topic_name(&msg.topic, &CONFIG.get_mqtt_topic_limits()) {
+        info!("client {:?} publish to invalid topic {:?}: {}", line.get_client_id(), msg.topic, e);
+        return Some(MqttMessageKind::Exit(DisconnectMessage::new(ReasonPhrases::TopicNameInvalid, None).into_vec()));
+    }
+    let message_id = msg.message_id;
+    // 载荷格式错误的消息不转发，QoS 1/2 以原因码 0x99 确认，连接保持
+    if msg.is_payload_format_invalid() {
+        info!("client {:?} publish invalid utf-8 payload to {:?}", line.get_client_id(), msg.topic);
+        let kind = match msg.qos {
+            MqttQos::Qos1 => TypeKind::PUBACK,
+            MqttQos::Qos2 => TypeKind::PUBREC,
+            _ => return None,
+        };
+        return Some(MqttMessageKind::Response(CommonPayloadMessage::with_code(kind, message_id, ReasonPhrases::PayloadFormatInvalid).into_vec()));
+    }
+    let from = line.get_client_id().to_owned();
+    match msg.qos {
+        MqttQos::Qos1 => {
+            let code = publish_code(publish_v5(from, msg).await);
+            Some(MqttMessageKind::Response(CommonPayloadMessage::with_code(TypeKind::PUBACK, message_id, code).into_vec()))
+        }
+        MqttQos::Qos2 => {
+            // 报文标识符在收到 PUBREL 之前重复出现时只回复 PUBREC，不再转发
+            let code = if line.session_mut().receive_qos2(message_id) {
+                publish_code(publish_v5(from, msg).await)
+            } else {
+                debug!("duplicate qos2 publish message id: {}", message_id);
+                ReasonPhrases::Success
+            };
+            Some(MqttMessageKind::Response(CommonPayloadMessage::with_code(TypeKind::PUBREC, message_id, code).into_vec()))
+        }
+        _ => {
+            publish_v5(from, msg).await;
+            None
+        }
+    }
+}
+
+fn publish_code(matched: usize) -> ReasonPhrases {
+    if matched == 0 { ReasonPhrases::NoMatchingSubscribers } else { ReasonPhrases::Success }
+}
+
+///
+/// 一个 SUBSCRIBE 报文中的所有主题过滤器只回复一个 SUBACK，之后按 Retain Handling 发送匹配的保留消息，
+/// 共享订阅设置 No Local 属于协议错误
+///
+async fn handle_v5_subscribe(line: &mut Line, items: &[MqttMessageV5]) -> MqttMessageKind {
+    let limits = CONFIG.get_mqtt_topic_limits();
+    let mut message_id = 0;
+    let mut codes = vec![];
+    let mut retain_messages = vec![];
+    for item in items {
+        if let MqttMessageV5::Subscribe(msg) = item {
+            debug!("{:?}", msg);
+            message_id = msg.message_id;
+            let topic = &msg.topic;
+            if let Err(e) = topic::validate_filter(topic, &limits) {
+                info!("client {:?} subscribe invalid topic filter {:?}: {}", line.get_client_id(), topic, e);
+                codes.push(ReasonPhrases::TopicFilterInvalid.as_byte());
+                continue;
+            }
+            let options = SubscriptionOptions::from(msg);
+            if options.subscription_identifier == Some(0) {
+                info!("client {:?} subscribe with subscription identifier 0", line.get_client_id());
+                return line.handle_protocol_error();
+            }
+            if options.no_local == MqttNoLocal::Enable && topic::split_shared(topic).is_some() {
+                info!("client {:?} subscribe shared topic filter {:?} with no local", line.get_client_id(), topic);
+                return line.handle_protocol_error();
+            }
+            let is_new = !SUBSCRIPT.is_subscript(topic, line.get_client_id()).await;
+            SUBSCRIPT.subscript(topic, line.get_client_id(), Subscriber::new(line.get_sender(), options)).await;
+            line.session_mut().subscribe(topic, options);
+            codes.push(options.qos.as_byte());
+            let send_retain = match options.retain_handling {
+                MqttRetainHandling::SendOnSubscribe => true,
+                MqttRetainHandling::SendIfNew => is_new,
+                MqttRetainHandling::DoNotSend => false,
+            };
+            if send_retain {
+                retain_messages.extend(get_retain_messages(line, topic, options).await);
+            }
+        }
+    }
+    let mut res = SubackMessage::new(message_id, codes, None).into_vec();
+    res.extend(retain_messages.concat());
+    MqttMessageKind::Response(res)
+}
+
+///
+/// 一个 UNSUBSCRIBE 报文只回复一个 UNSUBACK，没有订阅过的主题过滤器原因码为 0x11
+///
+async fn handle_v5_unsubscribe(line: &mut Line, items: &[MqttMessageV5]) -> MqttMessageKind {
+    let limits = CONFIG.get_mqtt_topic_limits();
+    let mut message_id = 0;
+    let mut codes = vec![];
+    for item in items {
+        if let MqttMessageV5::Unsubscribe(msg) = item {
+            debug!("topic name: {}", &msg.topic);
+            message_id = msg.message_id;
+            if let Err(e) = topic::validate_filter(&msg.topic, &limits) {
+                info!("client {:?} unsubscribe invalid topic filter {:?}: {}", line.get_client_id(), msg.topic, e);
+                codes.push(ReasonPhrases::TopicFilterInvalid);
+                continue;
+            }
+            if SUBSCRIPT.is_subscript(&msg.topic, line.get_client_id()).await {
+                SUBSCRIPT.unsubscript(&msg.topic, line.get_client_id()).await;
+                codes.push(ReasonPhrases::Success);
+            } else {
+                codes.push(ReasonPhrases::NoSubscriptionExisted);
+            }
+            line.session_mut().unsubscribe(&msg.topic);
+        }
+    }
+    MqttMessageKind::Response(UnsubackMessage::from_codes(message_id, codes).into_vec())
+}
+
+///
+/// 客户端断开连接，原因码为 0x04 时仍然发布遗嘱消息，DISCONNECT 可以更新会话过期时间
+///
+fn handle_v5_disconnect(line: &mut Line, msg: &DisconnectMessage) -> MqttMessageKind {
+    info!("client {:?} disconnect: {:#04x}", line.get_client_id(), msg.code);
+    let interval = msg.properties.as_deref().and_then(|properties| find_property(properties, Property::SessionExpiryInterval)).and_then(PropertyItem::as_long);
+    if let Some(interval) = interval {
+        if !line.update_session_expiry(interval) {
+            info!("client {:?} set session expiry interval after connecting with 0", line.get_client_id());
+            return line.handle_protocol_error();
+        }
+    }
+    if msg.code != ReasonPhrases::DisconnectWithWillMessage.as_byte() {
+        line.clear_will();
+    }
+    MqttMessageKind::Exit(vec![])
+}